@@ -0,0 +1,248 @@
+/*
+ * Parse the version 1 catalog that pkg(5) keeps for each publisher in a
+ * repository, under "publisher/<publisher>/catalog".  The catalog is made up
+ * of a set of JSON files:
+ *
+ *      catalog.attrs           the list of parts, package counts, etc
+ *      catalog.base.C          every package version, with signatures
+ *      catalog.dependency.C    depend actions, and obsolete/renamed markers
+ *      catalog.summary.C       the summary and description of each package
+ *
+ * Each part is an object keyed first by publisher and then by package stem,
+ * with an array of version entries for each stem.
+ */
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use super::ips::*;
+
+#[derive(Deserialize)]
+struct CatalogAttrs {
+    version: u32,
+    parts: BTreeMap<String, serde_json::Value>,
+    #[serde(rename = "package-version-count")]
+    package_version_count: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct PartEntry {
+    version: String,
+    #[serde(default)]
+    actions: Vec<String>,
+    #[serde(flatten)]
+    extra: BTreeMap<String, serde_json::Value>,
+}
+
+type PartStems = BTreeMap<String, Vec<PartEntry>>;
+
+#[derive(Debug, Clone)]
+pub struct CatalogEntry {
+    pkg: Package,
+    obsolete: bool,
+    renamed: bool,
+    summary: Option<String>,
+    signatures: BTreeMap<String, String>,
+}
+
+impl CatalogEntry {
+    pub fn pkg(&self) -> &Package {
+        &self.pkg
+    }
+
+    pub fn obsolete(&self) -> bool {
+        self.obsolete
+    }
+
+    pub fn renamed(&self) -> bool {
+        self.renamed
+    }
+
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
+    /**
+     * Signatures are keyed by algorithm; e.g., "sha-1" for the manifest
+     * signature reported as "signature-sha-1" in the catalog.
+     */
+    pub fn signatures(&self) -> &BTreeMap<String, String> {
+        &self.signatures
+    }
+}
+
+pub struct Catalog {
+    entries: Vec<CatalogEntry>,
+}
+
+impl Catalog {
+    /**
+     * Does this directory appear to contain a catalog?
+     */
+    pub fn exists<P: AsRef<Path>>(dir: P) -> bool {
+        dir.as_ref().join("catalog.attrs").is_file()
+    }
+
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Catalog> {
+        let dir = dir.as_ref();
+
+        let attrs: CatalogAttrs = read_json(&dir.join("catalog.attrs"))?;
+        if attrs.version != 1 {
+            bail!(
+                "catalog {:?} has unsupported version {}",
+                dir,
+                attrs.version
+            );
+        }
+        if !attrs.parts.contains_key("catalog.base.C") {
+            bail!("catalog {:?} has no base part", dir);
+        }
+
+        /*
+         * The base part contains an entry for every package version, so it
+         * determines the list of packages.  The other parts are optional, and
+         * only decorate entries that already exist.
+         */
+        let base = read_part(&dir.join("catalog.base.C"))?;
+        let mut entries: BTreeMap<(String, String, String), CatalogEntry> =
+            BTreeMap::new();
+        for (publisher, stems) in base {
+            for (stem, versions) in stems {
+                for pe in versions {
+                    let pkg = Package::parse_fmri(&format!(
+                        "pkg://{}/{}@{}",
+                        publisher, stem, pe.version
+                    ))?;
+                    let signatures = pe
+                        .extra
+                        .iter()
+                        .filter_map(|(k, v)| {
+                            Some((
+                                k.strip_prefix("signature-")?.to_string(),
+                                v.as_str()?.to_string(),
+                            ))
+                        })
+                        .collect();
+
+                    let key = (publisher.clone(), stem.clone(), pe.version);
+                    entries.insert(
+                        key,
+                        CatalogEntry {
+                            pkg,
+                            obsolete: false,
+                            renamed: false,
+                            summary: None,
+                            signatures,
+                        },
+                    );
+                }
+            }
+        }
+
+        for part in ["catalog.dependency.C", "catalog.summary.C"] {
+            if !attrs.parts.contains_key(part) {
+                continue;
+            }
+
+            for (publisher, stems) in read_part(&dir.join(part))? {
+                for (stem, versions) in stems {
+                    for pe in versions {
+                        let key = (publisher.clone(), stem.clone(), pe.version);
+                        let ce = if let Some(ce) = entries.get_mut(&key) {
+                            ce
+                        } else {
+                            bail!("{} entry {:?} not in base part", part, key);
+                        };
+
+                        for a in pe.actions.iter() {
+                            let (name, value) = if let Some(nv) = set_action(a)
+                                .with_context(|| {
+                                    format!("{} entry {:?}", part, key)
+                                })? {
+                                nv
+                            } else {
+                                continue;
+                            };
+
+                            match name.as_str() {
+                                "pkg.obsolete" => {
+                                    ce.obsolete = value == "true";
+                                }
+                                "pkg.renamed" => {
+                                    ce.renamed = value == "true";
+                                }
+                                "pkg.summary" => {
+                                    ce.summary = Some(value);
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(count) = attrs.package_version_count {
+            if count != entries.len() {
+                bail!(
+                    "catalog {:?} claims {} package versions, found {}",
+                    dir,
+                    count,
+                    entries.len()
+                );
+            }
+        }
+
+        Ok(Catalog {
+            entries: entries.into_values().collect(),
+        })
+    }
+
+    pub fn entries(&self) -> &[CatalogEntry] {
+        self.entries.as_slice()
+    }
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+    let f = std::fs::File::open(path)
+        .with_context(|| format!("opening {:?}", path))?;
+    serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("parsing {:?}", path))
+}
+
+/**
+ * Read a catalog part, skipping over the "_SIGNATURE" object and any other
+ * reserved keys, which all begin with an underscore.
+ */
+fn read_part(path: &Path) -> Result<BTreeMap<String, PartStems>> {
+    let raw: BTreeMap<String, serde_json::Value> = read_json(path)?;
+
+    raw.into_iter()
+        .filter(|(publisher, _)| !publisher.starts_with('_'))
+        .map(|(publisher, v)| {
+            let stems: PartStems = serde_json::from_value(v)
+                .with_context(|| format!("parsing {:?}", path))?;
+            Ok((publisher, stems))
+        })
+        .collect()
+}
+
+/**
+ * The dependency and summary parts carry a list of actions for each package
+ * version.  We are only interested in the name and value of "set" actions.
+ */
+fn set_action(action: &str) -> Result<Option<(String, String)>> {
+    if !action.starts_with("set ") {
+        return Ok(None);
+    }
+
+    match parse_action(action)? {
+        Action::Set(a) => {
+            Ok(Some((a.name().to_string(), a.value().to_string())))
+        }
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,114 @@
+/*
+ * Find every page that would collide with another once the mapping has been
+ * applied to the database.  Two pages collide if they end up with the same
+ * section and name, whether each is a file or a link, and whether one of them
+ * stayed where it was or both moved there.  We also report pages that end up
+ * with the same name in a section and one of its subsections (e.g., "4" and
+ * "4D"), where they did not share a section before; "man 4 foo" can then
+ * show only one of them.
+ */
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::mapping::{main_section, Mapping};
+use super::Database;
+
+#[derive(Clone)]
+pub struct Side {
+    pub link: bool,
+    pub pkg: String,
+    pub old: (String, String),
+    pub new: (String, String),
+}
+
+impl Side {
+    pub fn moved(&self) -> bool {
+        self.old != self.new
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    /**
+     * Two files, or two links, with the same new section and name.
+     */
+    Page,
+    LinkFile,
+    Subsection,
+}
+
+pub struct Collision {
+    pub kind: Kind,
+    pub sides: Vec<Side>,
+}
+
+pub fn analyse(db: &Database, map: &Mapping) -> Vec<Collision> {
+    let mut exact: BTreeMap<(String, String), Vec<Side>> = BTreeMap::new();
+    for r in db.records() {
+        let old = (r.sect.to_string(), r.page.to_string());
+        let new = map.page(&r.sect, &r.page).unwrap_or_else(|| old.clone());
+        exact.entry(new.clone()).or_default().push(Side {
+            link: r.link,
+            pkg: r.pkg.to_string(),
+            old,
+            new,
+        });
+    }
+
+    let mut out = Vec::new();
+
+    /*
+     * Group the pages by name and section, without any subsection, so that
+     * we can look for clashes between subsections.
+     */
+    let mut similar: BTreeMap<(String, String), Vec<&Side>> = BTreeMap::new();
+    for ((sect, page), sides) in exact.iter() {
+        if sides.len() > 1 {
+            continue;
+        }
+        let key = (main_section(sect).to_string(), page.to_string());
+        similar.entry(key).or_default().push(&sides[0]);
+    }
+    for sides in similar.values() {
+        if sides.len() < 2 || !sides.iter().any(|s| s.moved()) {
+            continue;
+        }
+
+        /*
+         * Pages that already shared a section and name are not new.
+         */
+        let before = sides
+            .iter()
+            .map(|s| (main_section(&s.old.0), s.old.1.as_str()))
+            .collect::<BTreeSet<_>>();
+        if before.len() == 1 {
+            continue;
+        }
+
+        out.push(Collision {
+            kind: Kind::Subsection,
+            sides: sides.iter().map(|s| (*s).clone()).collect(),
+        });
+    }
+
+    for sides in exact.into_values() {
+        if sides.len() < 2 {
+            continue;
+        }
+
+        let kind =
+            if sides.iter().any(|s| s.link) && sides.iter().any(|s| !s.link) {
+                Kind::LinkFile
+            } else {
+                Kind::Page
+            };
+        out.push(Collision { kind, sides });
+    }
+
+    out.sort_by(|a, b| {
+        a.kind
+            .cmp(&b.kind)
+            .then_with(|| a.sides[0].new.cmp(&b.sides[0].new))
+    });
+    out
+}
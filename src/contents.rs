@@ -5,10 +5,16 @@ use std::sync::{Mutex, Arc};
 use anyhow::Result;
 
 use super::ips::*;
-use super::pkgrepo_contents;
+use super::repo::Repository;
+use super::repo_contents;
 
 pub struct PkgContentsWorkItem {
     pub repo: String,
+    /**
+     * The file repository, if that is what we are reading, opened once and
+     * shared by every work item.
+     */
+    pub files: Option<Arc<Repository>>,
     pub pkg: Package,
 }
 
@@ -44,7 +50,7 @@ impl PkgContents {
     pub fn run(self, nthr: usize) -> mpsc::Receiver<Vec<PkgContentsResult>> {
         let (tx, rx) = mpsc::sync_channel::<Vec<PkgContentsResult>>(0);
         thread::Builder::new().name("r".to_string()).spawn(move || {
-            let mut threads = (0..nthr).into_iter().map(|n| {
+            let mut threads = (0..nthr).map(|n| {
                 let q = Arc::clone(&self.q);
                 let tx = tx.clone();
 
@@ -58,7 +64,11 @@ impl PkgContents {
                         };
 
                         let r = g.group.iter().map(|i| {
-                            let contents = pkgrepo_contents(&i.repo, &i.pkg)?;
+                            let contents = repo_contents(
+                                &i.repo,
+                                i.files.as_deref(),
+                                &i.pkg,
+                            )?;
                             Ok(PkgContentsResult {
                                 repo: i.repo.clone(),
                                 pkg: i.pkg.clone(),
@@ -0,0 +1,177 @@
+/*
+ * Produce unified diffs for review, based on the longest common subsequence
+ * of the lines in each version.  This takes time and space proportional to
+ * the product of the lengths of the parts that differ, which is fine for the
+ * manifests, Makefiles and manual pages that we rewrite.
+ */
+
+const CONTEXT: usize = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Same(usize, usize),
+    Remove(usize),
+    Add(usize),
+}
+
+fn edits(before: &[String], after: &[String]) -> Vec<Op> {
+    /*
+     * Lines common to the start and the end of both files are matched up
+     * directly, so that we need only compare the parts in between.
+     */
+    let pre = before
+        .iter()
+        .zip(after.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suf = before[pre..]
+        .iter()
+        .rev()
+        .zip(after[pre..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (n, m) = (before.len() - suf, after.len() - suf);
+
+    /*
+     * lcs[i][j] is the length of the longest common subsequence of
+     * before[pre + i..n] and after[pre + j..m].
+     */
+    let (bm, am) = (&before[pre..n], &after[pre..m]);
+    let mut lcs = vec![vec![0u32; am.len() + 1]; bm.len() + 1];
+    for i in (0..bm.len()).rev() {
+        for j in (0..am.len()).rev() {
+            lcs[i][j] = if bm[i] == am[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = (0..pre).map(|i| Op::Same(i, i)).collect::<Vec<_>>();
+    let (mut i, mut j) = (0, 0);
+    while i < bm.len() || j < am.len() {
+        if i < bm.len() && j < am.len() && bm[i] == am[j] {
+            out.push(Op::Same(pre + i, pre + j));
+            i += 1;
+            j += 1;
+        } else if i < bm.len()
+            && (j == am.len() || lcs[i + 1][j] >= lcs[i][j + 1])
+        {
+            /*
+             * Prefer removals, so that a run of changed lines is shown as all
+             * of the removals followed by all of the additions.
+             */
+            out.push(Op::Remove(pre + i));
+            i += 1;
+        } else {
+            out.push(Op::Add(pre + j));
+            j += 1;
+        }
+    }
+    out.extend((0..suf).map(|k| Op::Same(n + k, m + k)));
+    out
+}
+
+/**
+ * Hunk headers give the first line and the number of lines on each side.  An
+ * empty range is described by the line before it.
+ */
+fn range(first: usize, len: usize) -> String {
+    if len == 0 {
+        format!("{},0", first)
+    } else {
+        format!("{},{}", first + 1, len)
+    }
+}
+
+fn render(old: &str, new: &str, before: &[String], after: &[String]) -> String {
+    let ops = edits(before, after);
+    let changed = (0..ops.len())
+        .filter(|&k| !matches!(ops[k], Op::Same(..)))
+        .collect::<Vec<_>>();
+    if changed.is_empty() {
+        return String::new();
+    }
+
+    /*
+     * Work out the range of edits covered by each hunk, merging hunks whose
+     * context would otherwise overlap or abut.
+     */
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for &k in changed.iter() {
+        let start = k.saturating_sub(CONTEXT);
+        let end = (k + CONTEXT + 1).min(ops.len());
+        match hunks.last_mut() {
+            Some(h) if start <= h.1 => h.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", old, new);
+    for (start, end) in hunks {
+        /*
+         * Count the lines on each side that come before the hunk.
+         */
+        let (mut oi, mut ni) = (0, 0);
+        for op in ops[..start].iter() {
+            match op {
+                Op::Same(i, j) => (oi, ni) = (i + 1, j + 1),
+                Op::Remove(i) => oi = i + 1,
+                Op::Add(j) => ni = j + 1,
+            }
+        }
+
+        let mut body = String::new();
+        let (mut ol, mut nl) = (0, 0);
+        for op in ops[start..end].iter() {
+            match *op {
+                Op::Same(i, _) => {
+                    body += &format!(" {}\n", before[i]);
+                    ol += 1;
+                    nl += 1;
+                }
+                Op::Remove(i) => {
+                    body += &format!("-{}\n", before[i]);
+                    ol += 1;
+                }
+                Op::Add(j) => {
+                    body += &format!("+{}\n", after[j]);
+                    nl += 1;
+                }
+            }
+        }
+
+        out += &format!("@@ -{} +{} @@\n", range(oi, ol), range(ni, nl));
+        out += &body;
+    }
+
+    out
+}
+
+/**
+ * Render the differences between two versions of a file.  Returns an empty
+ * string if they are identical.
+ */
+pub fn unified(name: &str, before: &[String], after: &[String]) -> String {
+    render(
+        &format!("a/{}", name),
+        &format!("b/{}", name),
+        before,
+        after,
+    )
+}
+
+/**
+ * Render the creation of a new file.
+ */
+pub fn created(name: &str, after: &[String]) -> String {
+    render("/dev/null", &format!("b/{}", name), &[], after)
+}
+
+/**
+ * Render the removal of a file.
+ */
+pub fn removed(name: &str, before: &[String]) -> String {
+    render(&format!("a/{}", name), "/dev/null", before, &[])
+}
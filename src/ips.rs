@@ -1,14 +1,262 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use std::convert::{TryFrom, TryInto};
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Display;
 
+/**
+ * A dot-separated sequence of non-negative integers, like "5.11" or
+ * "2022.0.0.21234".  Sequences are compared element by element, and where one
+ * is a prefix of the other the shorter sequence sorts first.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DotSequence(Vec<u64>);
+
+impl Display for DotSequence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, n) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            write!(f, "{}", n)?;
+        }
+        Ok(())
+    }
+}
+
+impl DotSequence {
+    pub fn parse(input: &str) -> Result<DotSequence> {
+        let mut out = Vec::new();
+
+        for t in input.split('.') {
+            if t.is_empty() || !t.chars().all(|c| c.is_ascii_digit()) {
+                bail!("invalid dot sequence \"{}\"", input);
+            }
+            if t.len() > 1 && t.starts_with('0') {
+                bail!("leading zero in dot sequence \"{}\"", input);
+            }
+            out.push(t.parse()?);
+        }
+
+        Ok(DotSequence(out))
+    }
+
+    pub fn values(&self) -> &[u64] {
+        self.0.as_slice()
+    }
+}
+
+/**
+ * A pkg(5) package version, of the form:
+ *
+ *      release[,build_release][-branch][:timestamp]
+ *
+ * e.g., "0.5.11,5.11-2022.0.0.21234:20220314T011725Z".  Versions are ordered
+ * the way pkg(5) orders them: by release, then by branch, then by timestamp.
+ * A missing branch or timestamp sorts before any present value.  The build
+ * release is not significant to pkg(5), but we use it to break ties so that
+ * the ordering agrees with equality.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Version {
+    release: DotSequence,
+    build_release: Option<DotSequence>,
+    branch: Option<DotSequence>,
+    timestamp: Option<String>,
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.release
+            .cmp(&other.release)
+            .then_with(|| self.branch.cmp(&other.branch))
+            .then_with(|| self.timestamp.cmp(&other.timestamp))
+            .then_with(|| self.build_release.cmp(&other.build_release))
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.release)?;
+        if let Some(b) = &self.build_release {
+            write!(f, ",{}", b)?;
+        }
+        if let Some(b) = &self.branch {
+            write!(f, "-{}", b)?;
+        }
+        if let Some(t) = &self.timestamp {
+            write!(f, ":{}", t)?;
+        }
+        Ok(())
+    }
+}
+
+impl Version {
+    pub fn parse(input: &str) -> Result<Version> {
+        let (rest, timestamp) = match input.split_once(':') {
+            Some((r, t)) => (
+                r,
+                Some(
+                    parse_timestamp(t)
+                        .with_context(|| format!("in version \"{}\"", input))?,
+                ),
+            ),
+            None => (input, None),
+        };
+
+        let (rest, branch) = match rest.split_once('-') {
+            Some((r, b)) => (r, Some(DotSequence::parse(b)?)),
+            None => (rest, None),
+        };
+
+        let (release, build_release) = match rest.split_once(',') {
+            Some((r, b)) => (r, Some(DotSequence::parse(b)?)),
+            None => (rest, None),
+        };
+
+        Ok(Version {
+            release: DotSequence::parse(release)?,
+            build_release,
+            branch,
+            timestamp,
+        })
+    }
+
+    pub fn release(&self) -> &DotSequence {
+        &self.release
+    }
+
+    pub fn build_release(&self) -> Option<&DotSequence> {
+        self.build_release.as_ref()
+    }
+
+    pub fn branch(&self) -> Option<&DotSequence> {
+        self.branch.as_ref()
+    }
+
+    pub fn timestamp(&self) -> Option<&str> {
+        self.timestamp.as_deref()
+    }
+}
+
+/**
+ * Timestamps are in the basic ISO 8601 form, e.g., "20220314T011725Z", which
+ * sorts correctly as a string.
+ */
+pub fn parse_timestamp(input: &str) -> Result<String> {
+    let b = input.as_bytes();
+    if b.len() != 16
+        || b[8] != b'T'
+        || b[15] != b'Z'
+        || !b[..8].iter().all(u8::is_ascii_digit)
+        || !b[9..15].iter().all(u8::is_ascii_digit)
+    {
+        bail!("invalid timestamp \"{}\"", input);
+    }
+    Ok(input.to_string())
+}
+
+/**
+ * When a repository contains more than one version of a package, we generally
+ * want only one of them.  By default that is the newest version, but it can
+ * also be the newest version that is no newer than a particular branch (i.e.,
+ * build) or timestamp.
+ */
+#[derive(Debug, Clone)]
+pub enum VersionPin {
+    Newest,
+    Branch(DotSequence),
+    Timestamp(String),
+}
+
+impl Display for VersionPin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VersionPin::Newest => write!(f, "newest"),
+            VersionPin::Branch(b) => write!(f, "branch <= {}", b),
+            VersionPin::Timestamp(t) => write!(f, "timestamp <= {}", t),
+        }
+    }
+}
+
+impl VersionPin {
+    fn admits(&self, v: Option<&Version>) -> bool {
+        match self {
+            VersionPin::Newest => true,
+            /*
+             * A version without a branch or timestamp cannot be shown to
+             * be no newer than the pin, so it is not admitted.
+             */
+            VersionPin::Branch(pin) => {
+                v.and_then(|v| v.branch()).is_some_and(|b| b <= pin)
+            }
+            VersionPin::Timestamp(pin) => v
+                .and_then(|v| v.timestamp())
+                .is_some_and(|t| t <= pin.as_str()),
+        }
+    }
+}
+
+/**
+ * Select one version of each package stem, per the pin.  Stems for which no
+ * version is admitted by the pin are dropped entirely.  A stem offered by
+ * more than one publisher is taken from the preferred publisher; it is an
+ * error if none of them is preferred.
+ */
+pub fn select_versions(
+    pkgs: &[Package],
+    pin: &VersionPin,
+    prefer: Option<&str>,
+) -> Result<Vec<Package>> {
+    type Newest<'a> = BTreeMap<Option<&'a str>, &'a Package>;
+    let mut stems: BTreeMap<&str, Newest> = BTreeMap::new();
+
+    for pkg in pkgs.iter() {
+        if !pin.admits(pkg.version()) {
+            continue;
+        }
+
+        let e = stems
+            .entry(pkg.name())
+            .or_default()
+            .entry(pkg.publisher())
+            .or_insert(pkg);
+        if pkg.version() > e.version() {
+            *e = pkg;
+        }
+    }
+
+    let mut out = Vec::new();
+    for (stem, newest) in stems {
+        let pkg = match prefer.and_then(|p| newest.get(&Some(p))) {
+            Some(pkg) => pkg,
+            None if newest.len() == 1 => newest.values().next().unwrap(),
+            None => bail!(
+                "{} is offered by publishers {}",
+                stem,
+                newest
+                    .keys()
+                    .map(|p| p.unwrap_or("(none)"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        out.push((*pkg).clone());
+    }
+
+    Ok(out)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Package {
     name: String,
     publisher: Option<String>,
-    version: Option<String>,
-    date: Option<String>,
+    version: Option<Version>,
 }
 
 impl Display for Package {
@@ -20,9 +268,6 @@ impl Display for Package {
         write!(f, "{}", self.name)?;
         if let Some(v) = &self.version {
             write!(f, "@{}", v)?;
-            if let Some(d) = &self.date {
-                write!(f, ":{}", d)?;
-            }
         }
         Ok(())
     }
@@ -33,8 +278,8 @@ impl Package {
         &self.name
     }
 
-    pub fn version(&self) -> Option<&str> {
-        self.version.as_deref()
+    pub fn version(&self) -> Option<&Version> {
+        self.version.as_ref()
     }
 
     pub fn publisher(&self) -> Option<&str> {
@@ -42,7 +287,7 @@ impl Package {
     }
 
     pub fn date(&self) -> Option<&str> {
-        self.date.as_deref()
+        self.version.as_ref().and_then(|v| v.timestamp())
     }
 
     /**
@@ -88,16 +333,15 @@ impl Package {
         };
 
         let t = input.split('@').collect::<Vec<_>>();
-        let (name, version, date) = match t.as_slice() {
-            [n] => (n.to_string(), None, None),
+        let (name, version) = match t.as_slice() {
+            [n] => (n.to_string(), None),
             [n, x] => {
-                let t = x.split(':').collect::<Vec<_>>();
-                match t.as_slice() {
-                    [v] => (n.to_string(), Some(v.to_string()), None),
-                    [v, d] => (n.to_string(), Some(v.to_string()),
-                        Some(d.to_string())),
-                    _ => bail!("too much : in \"{}\"", fmri),
+                if x.matches(':').count() > 1 {
+                    bail!("too much : in \"{}\"", fmri);
                 }
+                let v = Version::parse(x)
+                    .with_context(|| format!("in \"{}\"", fmri))?;
+                (n.to_string(), Some(v))
             }
             _ => bail!("too much @ in \"{}\"", fmri),
         };
@@ -106,7 +350,6 @@ impl Package {
             name,
             publisher,
             version,
-            date,
         })
     }
 }
@@ -167,8 +410,7 @@ pub struct ActionDepend {
     fmri: Vec<Package>,
     type_: DependType,
     predicate: Vec<String>,
-    variant_zone: Option<String>,
-    variant_imagetype: Option<String>,
+    vals: Vals,
 }
 
 impl ActionDepend {
@@ -219,12 +461,550 @@ impl ActionFile {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct ActionDir {
+    path: String,
+    owner: Option<String>,
+    group: Option<String>,
+    mode: Option<String>,
+    vals: Vals,
+}
+
+impl ActionDir {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn owner(&self) -> Option<&str> {
+        self.owner.as_deref()
+    }
+
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    pub fn mode(&self) -> Option<&str> {
+        self.mode.as_deref()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ActionHardlink {
+    path: String,
+    target: String,
+    vals: Vals,
+}
+
+impl ActionHardlink {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ActionSet {
+    name: String,
+    value: Vec<String>,
+    vals: Vals,
+}
+
+impl ActionSet {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /**
+     * Most set actions have a single value; return the first one.
+     */
+    pub fn value(&self) -> &str {
+        &self.value[0]
+    }
+
+    pub fn values(&self) -> &[String] {
+        self.value.as_slice()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ActionLicense {
+    license: String,
+    hash: Option<String>,
+    must_accept: bool,
+    must_display: bool,
+    vals: Vals,
+}
+
+impl ActionLicense {
+    pub fn license(&self) -> &str {
+        &self.license
+    }
+
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_deref()
+    }
+
+    pub fn must_accept(&self) -> bool {
+        self.must_accept
+    }
+
+    pub fn must_display(&self) -> bool {
+        self.must_display
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ActionDriver {
+    name: String,
+    alias: Vec<String>,
+    class: Vec<String>,
+    perms: Vec<String>,
+    clone_perms: Vec<String>,
+    privs: Vec<String>,
+    policy: Vec<String>,
+    devlink: Vec<String>,
+    vals: Vals,
+}
+
+impl ActionDriver {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn aliases(&self) -> &[String] {
+        self.alias.as_slice()
+    }
+
+    pub fn classes(&self) -> &[String] {
+        self.class.as_slice()
+    }
+
+    pub fn perms(&self) -> &[String] {
+        self.perms.as_slice()
+    }
+
+    pub fn clone_perms(&self) -> &[String] {
+        self.clone_perms.as_slice()
+    }
+
+    pub fn privs(&self) -> &[String] {
+        self.privs.as_slice()
+    }
+
+    pub fn policy(&self) -> &[String] {
+        self.policy.as_slice()
+    }
+
+    pub fn devlinks(&self) -> &[String] {
+        self.devlink.as_slice()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ActionUser {
+    username: String,
+    uid: Option<String>,
+    group: Option<String>,
+    gcos_field: Option<String>,
+    home_dir: Option<String>,
+    login_shell: Option<String>,
+    group_list: Vec<String>,
+    vals: Vals,
+}
+
+impl ActionUser {
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn uid(&self) -> Option<&str> {
+        self.uid.as_deref()
+    }
+
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    pub fn gcos_field(&self) -> Option<&str> {
+        self.gcos_field.as_deref()
+    }
+
+    pub fn home_dir(&self) -> Option<&str> {
+        self.home_dir.as_deref()
+    }
+
+    pub fn login_shell(&self) -> Option<&str> {
+        self.login_shell.as_deref()
+    }
+
+    pub fn group_list(&self) -> &[String] {
+        self.group_list.as_slice()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ActionGroup {
+    groupname: String,
+    gid: Option<String>,
+    vals: Vals,
+}
+
+impl ActionGroup {
+    pub fn groupname(&self) -> &str {
+        &self.groupname
+    }
+
+    pub fn gid(&self) -> Option<&str> {
+        self.gid.as_deref()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ActionLegacy {
+    pkg: String,
+    name: Option<String>,
+    desc: Option<String>,
+    version: Option<String>,
+    arch: Option<String>,
+    category: Option<String>,
+    vendor: Option<String>,
+    vals: Vals,
+}
+
+impl ActionLegacy {
+    pub fn pkg(&self) -> &str {
+        &self.pkg
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn desc(&self) -> Option<&str> {
+        self.desc.as_deref()
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    pub fn arch(&self) -> Option<&str> {
+        self.arch.as_deref()
+    }
+
+    pub fn category(&self) -> Option<&str> {
+        self.category.as_deref()
+    }
+
+    pub fn vendor(&self) -> Option<&str> {
+        self.vendor.as_deref()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ActionSignature {
+    value: String,
+    algorithm: String,
+    hash: Option<String>,
+    chain: Vec<String>,
+    vals: Vals,
+}
+
+impl ActionSignature {
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn algorithm(&self) -> &str {
+        &self.algorithm
+    }
+
+    /**
+     * The hash of the signing certificate, if the signature uses one.
+     */
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_deref()
+    }
+
+    pub fn chain(&self) -> &[String] {
+        self.chain.as_slice()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Action {
     Depend(ActionDepend),
     Unknown(String, Vec<String>, Vals),
     File(ActionFile),
     Link(ActionLink),
+    Dir(ActionDir),
+    Hardlink(ActionHardlink),
+    Set(ActionSet),
+    License(ActionLicense),
+    Driver(ActionDriver),
+    User(ActionUser),
+    Group(ActionGroup),
+    Legacy(ActionLegacy),
+    Signature(ActionSignature),
+}
+
+impl Action {
+    /**
+     * The action type, as it appears at the start of a manifest line.
+     */
+    pub fn name(&self) -> &str {
+        match self {
+            Action::Depend(_) => "depend",
+            Action::Unknown(n, _, _) => n.as_str(),
+            Action::File(_) => "file",
+            Action::Link(_) => "link",
+            Action::Dir(_) => "dir",
+            Action::Hardlink(_) => "hardlink",
+            Action::Set(_) => "set",
+            Action::License(_) => "license",
+            Action::Driver(_) => "driver",
+            Action::User(_) => "user",
+            Action::Group(_) => "group",
+            Action::Legacy(_) => "legacy",
+            Action::Signature(_) => "signature",
+        }
+    }
+
+    /**
+     * Every attribute of the action, in the order they appeared in the
+     * manifest.
+     */
+    pub fn vals(&self) -> &Vals {
+        match self {
+            Action::Depend(a) => &a.vals,
+            Action::Unknown(_, _, vals) => vals,
+            Action::File(a) => &a.vals,
+            Action::Link(a) => &a.vals,
+            Action::Dir(a) => &a.vals,
+            Action::Hardlink(a) => &a.vals,
+            Action::Set(a) => &a.vals,
+            Action::License(a) => &a.vals,
+            Action::Driver(a) => &a.vals,
+            Action::User(a) => &a.vals,
+            Action::Group(a) => &a.vals,
+            Action::Legacy(a) => &a.vals,
+            Action::Signature(a) => &a.vals,
+        }
+    }
+
+    /**
+     * Construct an action from its type, positional arguments and attributes.
+     * The result is checked in exactly the same way as an action read from a
+     * manifest.
+     */
+    pub fn from_parts(
+        name: &str,
+        positional: &[String],
+        attrs: &[(String, String)],
+    ) -> Result<Action> {
+        let mut l = name.to_string();
+        for p in positional {
+            l.push(' ');
+            l += p;
+        }
+        for (k, v) in attrs {
+            l += &format!(" {}={}", k, quote_value(v));
+        }
+        parse_action(&l)
+    }
+
+    /**
+     * The name of the attribute that identifies an action within a package;
+     * e.g., "path" for a file.
+     */
+    pub fn key_attr(&self) -> Option<&str> {
+        Some(match self.name() {
+            "file" | "dir" | "link" | "hardlink" => "path",
+            "depend" => "fmri",
+            "set" | "driver" => "name",
+            "license" => "license",
+            "user" => "username",
+            "group" => "groupname",
+            "legacy" => "pkg",
+            "signature" => "value",
+            _ => return None,
+        })
+    }
+
+    /**
+     * Positional (i.e., non key=value) arguments; usually the payload hash.
+     * If the hash was instead provided as an attribute, we leave it there.
+     */
+    pub fn positional(&self) -> Vec<&str> {
+        let hash = match self {
+            Action::Unknown(_, free, _) => {
+                return free.iter().map(String::as_str).collect();
+            }
+            Action::File(a) => a.fileid.as_deref(),
+            Action::License(a) => a.hash.as_deref(),
+            Action::Signature(a) => a.hash.as_deref(),
+            _ => None,
+        };
+
+        match hash {
+            Some(h) if self.vals().get("hash").is_none() => vec![h],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/**
+ * Select actions for a particular set of variant values, as pkg(5) would when
+ * installing into an image.  Each constraint is written "name=value", where
+ * the name may omit the "variant." prefix, and the following shorthands are
+ * accepted:
+ *
+ *      zone=global|nonglobal       variant.opensolaris.zone
+ *      imagetype=full|partial      variant.opensolaris.imagetype
+ *      debug=true|false            every variant.debug.* variant
+ *
+ * An action is excluded only if it is tagged with a variant that we have
+ * constrained, and its value for that variant does not match.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct VariantFilter {
+    exact: BTreeMap<String, String>,
+    prefixed: BTreeMap<String, String>,
+}
+
+impl VariantFilter {
+    pub fn parse<S: AsRef<str>>(specs: &[S]) -> Result<VariantFilter> {
+        let mut vf = VariantFilter::default();
+
+        for spec in specs {
+            let spec = spec.as_ref();
+            let (name, value) = if let Some(nv) = spec.split_once('=') {
+                nv
+            } else {
+                bail!("variant {:?} should be of the form name=value", spec);
+            };
+
+            let name = name.strip_prefix("variant.").unwrap_or(name);
+            match name {
+                "zone" | "imagetype" => {
+                    vf.exact.insert(
+                        format!("variant.opensolaris.{}", name),
+                        value.to_string(),
+                    );
+                }
+                "debug" => {
+                    vf.prefixed.insert(
+                        "variant.debug.".to_string(),
+                        value.to_string(),
+                    );
+                }
+                "" => bail!("variant {:?} has no name", spec),
+                _ => {
+                    vf.exact
+                        .insert(format!("variant.{}", name), value.to_string());
+                }
+            }
+        }
+
+        Ok(vf)
+    }
+
+    pub fn admits(&self, action: &Action) -> bool {
+        action.vals().variants().iter().all(|(k, v)| {
+            if let Some(want) = self.exact.get(k) {
+                return v == want;
+            }
+            self.prefixed
+                .iter()
+                .filter(|(p, _)| k.starts_with(p.as_str()))
+                .all(|(_, want)| v == want)
+        })
+    }
+
+    pub fn filter(&self, actions: &[Action]) -> Vec<Action> {
+        actions.iter().filter(|a| self.admits(a)).cloned().collect()
+    }
+}
+
+/**
+ * Actions are rendered in the canonical form that pkg(5) uses: the action
+ * type, then any payload hash, then each attribute in order of its name.
+ * Multiple values for one attribute are kept in their original order.
+ */
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())?;
+        for p in self.positional() {
+            write!(f, " {}", p)?;
+        }
+        for (k, v) in self.vals().sorted() {
+            write!(f, " {}={}", k, quote_value(v))?;
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Quote an attribute value, if required, so that parse_action() will read it
+ * back unchanged.  Like pkg(5), we prefer double quotes, then single quotes,
+ * and only escape quote characters when the value contains both kinds.
+ */
+fn quote_value(v: &str) -> String {
+    /*
+     * An unquoted value with a trailing backslash would look like a line
+     * continuation, so it must be quoted as well.
+     */
+    if !v.is_empty()
+        && !v.contains([' ', '"', '\''])
+        && !v.ends_with('\\')
+    {
+        return v.to_string();
+    }
+
+    let (quote, escape) = if !v.contains('"') {
+        ('"', false)
+    } else if !v.contains('\'') {
+        ('\'', false)
+    } else {
+        ('"', true)
+    };
+
+    let mut out = String::new();
+    out.push(quote);
+    let mut i = v.chars().peekable();
+    while let Some(c) = i.next() {
+        if c == quote && escape {
+            out.push('\\');
+        } else if c == '\\' {
+            /*
+             * A backslash is only special if it precedes another backslash
+             * or the closing quote; in those cases, it must be escaped.
+             */
+            match i.peek() {
+                None => out.push('\\'),
+                Some(&n) if n == '\\' || n == quote => out.push('\\'),
+                _ => {}
+            }
+        }
+        out.push(c);
+    }
+    out.push(quote);
+    out
+}
+
+/**
+ * Render a list of actions as a manifest, one action per line.
+ */
+pub fn format_manifest(actions: &[Action]) -> String {
+    let mut out = String::new();
+    for a in actions {
+        out += &a.to_string();
+        out.push('\n');
+    }
+    out
 }
 
 #[derive(Debug)]
@@ -234,14 +1014,17 @@ enum ParseState {
     Key,
     Value,
     ValueQuoted,
+    ValueQuotedEscape,
     ValueQuotedSpace,
     ValueUnquoted,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq)]
 pub struct Vals {
     vals: Vec<(String, String)>,
     extra: BTreeSet<String>,
+    facets: BTreeMap<String, String>,
+    variants: BTreeMap<String, String>,
 }
 
 impl Vals {
@@ -249,19 +1032,71 @@ impl Vals {
         Vals {
             vals: Vec::new(),
             extra: BTreeSet::new(),
+            facets: BTreeMap::new(),
+            variants: BTreeMap::new(),
         }
     }
 
-    fn insert(&mut self, key: &str, value: &str) {
+    fn insert(&mut self, key: &str, value: &str) -> Result<()> {
+        self.vals.push((key.to_string(), value.to_string()));
+
         /*
-         * XXX Ignore "facet.*" properties for now...
+         * Facets and variants may appear on any action.  We keep them in
+         * their own maps, rather than leaving them for each action type to
+         * consume.
          */
-        if key.starts_with("facet.") {
-            return;
+        let map = if key.starts_with("facet.") {
+            &mut self.facets
+        } else if key.starts_with("variant.") {
+            &mut self.variants
+        } else {
+            self.extra.insert(key.to_string());
+            return Ok(());
+        };
+
+        if map.insert(key.to_string(), value.to_string()).is_some() {
+            bail!("more than one value for {}", key);
         }
+        Ok(())
+    }
 
-        self.vals.push((key.to_string(), value.to_string()));
-        self.extra.insert(key.to_string());
+    /**
+     * Facets, keyed by their full name; e.g., "facet.doc.man".
+     */
+    pub fn facets(&self) -> &BTreeMap<String, String> {
+        &self.facets
+    }
+
+    /**
+     * Variants, keyed by their full name; e.g., "variant.arch".
+     */
+    pub fn variants(&self) -> &BTreeMap<String, String> {
+        &self.variants
+    }
+
+    /**
+     * Return the first value for a property, if there is one.  Unlike the
+     * other accessors, this does not mark the property as consumed.
+     */
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vals
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /**
+     * Every attribute, sorted by name.  The sort is stable, so multiple values
+     * for the same attribute remain in their original order.
+     */
+    pub fn sorted(&self) -> Vec<(&str, &str)> {
+        let mut out = self
+            .vals
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect::<Vec<_>>();
+        out.sort_by(|a, b| a.0.cmp(b.0));
+        out
     }
 
     fn maybe_single(&mut self, name: &str) -> Result<Option<String>> {
@@ -312,6 +1147,42 @@ impl Vals {
         Ok(out)
     }
 
+    fn maybe_bool(&mut self, name: &str) -> Result<bool> {
+        Ok(match self.maybe_single(name)?.as_deref() {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(v) => bail!("{} should be true or false, not {:?}", name, v),
+        })
+    }
+
+    /**
+     * Mark some set of properties as consumed without looking at them.
+     */
+    fn ignore(&mut self, names: &[&str]) {
+        for name in names {
+            self.extra.remove(*name);
+        }
+    }
+
+    fn ignore_prefixed(&mut self, prefix: &str) {
+        self.extra.retain(|k| !k.starts_with(prefix));
+    }
+
+    /**
+     * Properties that may appear on any action, and which we do not otherwise
+     * interpret.
+     */
+    fn ignore_common(&mut self) {
+        self.ignore_prefixed("pkg.linted");
+    }
+
+    /**
+     * Properties that describe the payload of actions that deliver content.
+     */
+    fn ignore_payload(&mut self) {
+        self.ignore(&["chash", "pkg.csize", "pkg.size", "pkg.content-hash"]);
+    }
+
     fn check_for_extra(&self) -> Result<()> {
         if !self.extra.is_empty() {
             bail!("some properties present but not consumed: {:?}, {:?}",
@@ -322,175 +1193,454 @@ impl Vals {
     }
 }
 
-pub fn parse_manifest(input: &str) -> Result<Vec<Action>> {
+/**
+ * A manifest is made up of a series of physical lines, which we join into
+ * logical lines in the way that pkg(5) does: leading whitespace is discarded,
+ * and a line ending in a backslash is continued on the next line (without the
+ * backslash).  Blank lines and comments, which begin with "#", are skipped.
+ * Each logical line is returned along with the (1-based) number of the first
+ * physical line from which it was assembled.
+ */
+pub fn manifest_lines(input: &str) -> Vec<(usize, String)> {
     let mut out = Vec::new();
+    let mut acc: Option<(usize, String)> = None;
 
-    for l in input.lines() {
-        let mut s = ParseState::Rest;
-        let mut a = String::new();
-        let mut k = String::new();
-        let mut v = String::new();
-        let mut vals = Vals::new();
-        let mut free: Vec<String> = Vec::new();
-        let mut quote = '"';
-
-        for c in l.chars() {
-            match s {
-                ParseState::Rest => {
-                    if c.is_ascii_alphabetic() {
-                        a.clear();
-                        k.clear();
-                        v.clear();
+    for (n, l) in input.lines().enumerate() {
+        let l = l.trim_start();
 
-                        a.push(c);
-                        s = ParseState::Type;
-                    } else {
-                        bail!("invalid line ({:?}): {}", s, l);
-                    }
+        let (n, mut l) = if let Some((an, mut a)) = acc.take() {
+            a.push_str(l);
+            (an, a)
+        } else {
+            (n + 1, l.to_string())
+        };
+
+        if l.ends_with('\\') {
+            l.pop();
+            acc = Some((n, l));
+            continue;
+        }
+
+        if l.trim_end().is_empty() || l.starts_with('#') {
+            continue;
+        }
+
+        out.push((n, l));
+    }
+
+    if let Some((n, l)) = acc {
+        /*
+         * A continuation on the last line of the file is joined with nothing.
+         */
+        if !l.trim_end().is_empty() && !l.starts_with('#') {
+            out.push((n, l));
+        }
+    }
+
+    out
+}
+
+pub fn parse_manifest(input: &str) -> Result<Vec<Action>> {
+    manifest_lines(input)
+        .iter()
+        .map(|(n, l)| {
+            parse_action(l).with_context(|| format!("manifest line {}", n))
+        })
+        .collect()
+}
+
+/**
+ * Parse a single logical manifest line into an action.
+ */
+pub fn parse_action(l: &str) -> Result<Action> {
+    let mut s = ParseState::Rest;
+    let mut a = String::new();
+    let mut k = String::new();
+    let mut v = String::new();
+    let mut vals = Vals::new();
+    let mut free: Vec<String> = Vec::new();
+    let mut quote = '"';
+
+    for c in l.chars() {
+        match s {
+            ParseState::Rest => {
+                if c.is_ascii_alphabetic() {
+                    a.clear();
+                    k.clear();
+                    v.clear();
+
+                    a.push(c);
+                    s = ParseState::Type;
+                } else {
+                    bail!("invalid line ({:?}): {}", s, l);
                 }
-                ParseState::Type => {
-                    if c.is_ascii_alphabetic() {
-                        a.push(c);
-                    } else if c == ' ' {
-                        s = ParseState::Key;
-                    } else {
-                        bail!("invalid line ({:?}): {}", s, l);
-                    }
+            }
+            ParseState::Type => {
+                if c.is_ascii_alphabetic() {
+                    a.push(c);
+                } else if c == ' ' {
+                    s = ParseState::Key;
+                } else {
+                    bail!("invalid line ({:?}): {}", s, l);
                 }
-                ParseState::Key => {
-                    if c.is_ascii_alphanumeric()
-                        || c == '.' || c == '-' || c == '_' || c == '/'
-                        || c == '@' || c == '+'
-                    {
-                        k.push(c);
-                    } else if c == ' ' {
+            }
+            ParseState::Key => {
+                if c.is_ascii_alphanumeric()
+                    || c == '.' || c == '-' || c == '_' || c == '/'
+                    || c == '@' || c == '+'
+                {
+                    k.push(c);
+                } else if c == ' ' {
+                    /*
+                     * Hand-written manifests may use more than one space
+                     * between attributes.
+                     */
+                    if !k.is_empty() {
                         free.push(k.clone());
                         k.clear();
-                    } else if c == '=' {
-                        s = ParseState::Value;
-                    } else {
-                        bail!("invalid line ({:?}, {}): {}", s, k, l);
                     }
+                } else if c == '=' {
+                    s = ParseState::Value;
+                } else {
+                    bail!("invalid line ({:?}, {}): {}", s, k, l);
                 }
-                ParseState::Value => {
+            }
+            ParseState::Value => {
+                /*
+                 * This state represents the start of a new value, which
+                 * will either be quoted or unquoted.
+                 */
+                v.clear();
+                if c == '"' || c == '\'' {
                     /*
-                     * This state represents the start of a new value, which
-                     * will either be quoted or unquoted.
+                     * Record the type of quote used at the start of the
+                     * string so that we can match it with the same type
+                     * of quote at the end.
                      */
-                    v.clear();
-                    if c == '"' || c == '\'' {
-                        /*
-                         * Record the type of quote used at the start of the
-                         * string so that we can match it with the same type
-                         * of quote at the end.
-                         */
-                        quote = c;
-                        s = ParseState::ValueQuoted;
-                    } else {
-                        s = ParseState::ValueUnquoted;
-                        v.push(c);
-                    }
+                    quote = c;
+                    s = ParseState::ValueQuoted;
+                } else {
+                    s = ParseState::ValueUnquoted;
+                    v.push(c);
                 }
-                ParseState::ValueQuoted => {
-                    if c == '\\' {
-                        /*
-                         * XXX handle escaped quotes...
-                         */
-                        bail!("invalid line (backslash...): {}", l);
-                    } else if c == quote {
-                        s = ParseState::ValueQuotedSpace;
-                    } else {
-                        v.push(c);
-                    }
-                }
-                ParseState::ValueQuotedSpace => {
-                    /*
-                     * We expect at least one space after a quoted string before
-                     * the next key.
-                     */
-                    if c == ' ' {
-                        vals.insert(&k, &v);
-                        s = ParseState::Key;
-                        k.clear();
-                    } else {
-                        bail!("invalid after quote ({:?}, {}): {}", s, k, l);
-                    }
+            }
+            ParseState::ValueQuoted => {
+                if c == '\\' {
+                    s = ParseState::ValueQuotedEscape;
+                } else if c == quote {
+                    s = ParseState::ValueQuotedSpace;
+                } else {
+                    v.push(c);
                 }
-                ParseState::ValueUnquoted => {
-                    if c == '"' || c == '\'' {
-                        bail!("invalid line (errant quote...): {}", l);
-                    } else if c == ' ' {
-                        vals.insert(&k, &v);
-                        s = ParseState::Key;
-                        k.clear();
-                    } else {
-                        v.push(c);
-                    }
+            }
+            ParseState::ValueQuotedEscape => {
+                /*
+                 * Within a quoted value, pkg(5) treats a backslash as an
+                 * escape only when it precedes another backslash or the
+                 * quote character that opened the value.  Any other
+                 * backslash is preserved as-is.
+                 */
+                if c != '\\' && c != quote {
+                    v.push('\\');
                 }
+                v.push(c);
+                s = ParseState::ValueQuoted;
             }
-        }
-
-        match s {
-            ParseState::ValueQuotedSpace | ParseState::ValueUnquoted => {
-                vals.insert(&k, &v);
-            }
-            ParseState::Type => {},
-            _ => bail!("invalid line (terminal state {:?}: {}", s, l),
-        }
-
-        match a.as_str() {
-            "depend" => {
-                let fmri = vals.list("fmri")?.iter()
-                    .map(|fmri| Package::parse_fmri(fmri.as_str()))
-                    .collect::<Result<Vec<_>>>()?;
-                let type_ = vals.single("type")?.try_into()?;
-                let predicate = vals.maybe_list("predicate");
-                let variant_zone = vals.maybe_single(
-                    "variant.opensolaris.zone")?;
-                let variant_imagetype = vals.maybe_single(
-                    "variant.opensolaris.imagetype")?;
+            ParseState::ValueQuotedSpace => {
                 /*
-                 * XXX Ignore...
+                 * We expect at least one space after a quoted string before
+                 * the next key.
                  */
-                vals.maybe_single("pkg.linted")?;
-
-                vals.check_for_extra()?;
-
-                out.push(Action::Depend(ActionDepend {
-                    fmri,
-                    type_,
-                    predicate,
-                    variant_zone,
-                    variant_imagetype,
-                }))
-            }
-            "file" => {
-                let path = vals.single("path")?;
-                if free.len() > 1 {
-                    bail!("more than one fileid? {:?}", free);
+                if c == ' ' {
+                    vals.insert(&k, &v)?;
+                    s = ParseState::Key;
+                    k.clear();
+                } else {
+                    bail!("invalid after quote ({:?}, {}): {}", s, k, l);
                 }
-                let fileid = free.pop();
-                out.push(Action::File(ActionFile {
-                    path,
-                    fileid,
-                    vals,
-                }))
-            }
-            "link" => {
-                let path = vals.single("path")?;
-                let target = vals.single("target")?;
-                if !free.is_empty() {
-                    bail!("spare arguments? {:?}", free);
+            }
+            ParseState::ValueUnquoted => {
+                if c == '"' || c == '\'' {
+                    bail!("invalid line (errant quote...): {}", l);
+                } else if c == ' ' {
+                    vals.insert(&k, &v)?;
+                    s = ParseState::Key;
+                    k.clear();
+                } else {
+                    v.push(c);
                 }
-                out.push(Action::Link(ActionLink {
-                    path,
-                    target,
-                    vals,
-                }))
             }
-            _ => out.push(Action::Unknown(a.to_string(), free, vals)),
         }
     }
 
-    Ok(out)
+    match s {
+        ParseState::ValueQuotedSpace | ParseState::ValueUnquoted => {
+            vals.insert(&k, &v)?;
+        }
+        ParseState::Key => {
+            if !k.is_empty() {
+                free.push(k.clone());
+            }
+        }
+        ParseState::Type => {},
+        _ => bail!("invalid line (terminal state {:?}: {}", s, l),
+    }
+
+    Ok(match a.as_str() {
+        "depend" => {
+            let fmri = vals.list("fmri")?.iter()
+                .map(|fmri| Package::parse_fmri(fmri.as_str()))
+                .collect::<Result<Vec<_>>>()?;
+            let type_ = vals.single("type")?.try_into()?;
+            let predicate = vals.maybe_list("predicate");
+            vals.ignore_common();
+
+            vals.check_for_extra()?;
+
+            Action::Depend(ActionDepend {
+                fmri,
+                type_,
+                predicate,
+                vals,
+            })
+        }
+        "file" => {
+            let path = vals.single("path")?;
+            if free.len() > 1 {
+                bail!("more than one fileid? {:?}", free);
+            }
+            let fileid = free.pop();
+            Action::File(ActionFile {
+                path,
+                fileid,
+                vals,
+            })
+        }
+        "link" => {
+            let path = vals.single("path")?;
+            let target = vals.single("target")?;
+            if !free.is_empty() {
+                bail!("spare arguments? {:?}", free);
+            }
+            Action::Link(ActionLink {
+                path,
+                target,
+                vals,
+            })
+        }
+        "dir" => {
+            let path = vals.single("path")?;
+            let owner = vals.maybe_single("owner")?;
+            let group = vals.maybe_single("group")?;
+            let mode = vals.maybe_single("mode")?;
+            vals.ignore(&["salvage-from", "revert-tag"]);
+            vals.ignore_common();
+            if !free.is_empty() {
+                bail!("spare arguments? {:?}", free);
+            }
+
+            vals.check_for_extra()?;
+
+            Action::Dir(ActionDir {
+                path,
+                owner,
+                group,
+                mode,
+                vals,
+            })
+        }
+        "hardlink" => {
+            let path = vals.single("path")?;
+            let target = vals.single("target")?;
+            vals.ignore(&[
+                "mediator",
+                "mediator-version",
+                "mediator-implementation",
+                "mediator-priority",
+            ]);
+            vals.ignore_common();
+            if !free.is_empty() {
+                bail!("spare arguments? {:?}", free);
+            }
+
+            vals.check_for_extra()?;
+
+            Action::Hardlink(ActionHardlink {
+                path,
+                target,
+                vals,
+            })
+        }
+        "set" => {
+            let name = vals.single("name")?;
+            let value = vals.list("value")?;
+            vals.ignore_common();
+            if !free.is_empty() {
+                bail!("spare arguments? {:?}", free);
+            }
+
+            vals.check_for_extra()?;
+
+            Action::Set(ActionSet {
+                name,
+                value,
+                vals,
+            })
+        }
+        "license" => {
+            let license = vals.single("license")?;
+            let must_accept = vals.maybe_bool("must-accept")?;
+            let must_display = vals.maybe_bool("must-display")?;
+            /*
+             * The payload hash is usually positional, but may also be
+             * provided as an attribute.
+             */
+            let hash = match (free.len(), vals.maybe_single("hash")?) {
+                (0, h) => h,
+                (1, None) => free.pop(),
+                _ => bail!("more than one hash? {:?}", free),
+            };
+            vals.ignore_payload();
+            vals.ignore_common();
+
+            vals.check_for_extra()?;
+
+            Action::License(ActionLicense {
+                license,
+                hash,
+                must_accept,
+                must_display,
+                vals,
+            })
+        }
+        "driver" => {
+            let name = vals.single("name")?;
+            let alias = vals.maybe_list("alias");
+            let class = vals.maybe_list("class");
+            let perms = vals.maybe_list("perms");
+            let clone_perms = vals.maybe_list("clone_perms");
+            let privs = vals.maybe_list("privs");
+            let policy = vals.maybe_list("policy");
+            let devlink = vals.maybe_list("devlink");
+            vals.ignore_common();
+            if !free.is_empty() {
+                bail!("spare arguments? {:?}", free);
+            }
+
+            vals.check_for_extra()?;
+
+            Action::Driver(ActionDriver {
+                name,
+                alias,
+                class,
+                perms,
+                clone_perms,
+                privs,
+                policy,
+                devlink,
+                vals,
+            })
+        }
+        "user" => {
+            let username = vals.single("username")?;
+            let uid = vals.maybe_single("uid")?;
+            let group = vals.maybe_single("group")?;
+            let gcos_field = vals.maybe_single("gcos-field")?;
+            let home_dir = vals.maybe_single("home-dir")?;
+            let login_shell = vals.maybe_single("login-shell")?;
+            let group_list = vals.maybe_list("group-list");
+            vals.ignore(&[
+                "password", "ftpuser", "lastchg", "min", "max", "warn",
+                "inactive", "expire", "flag",
+            ]);
+            vals.ignore_common();
+            if !free.is_empty() {
+                bail!("spare arguments? {:?}", free);
+            }
+
+            vals.check_for_extra()?;
+
+            Action::User(ActionUser {
+                username,
+                uid,
+                group,
+                gcos_field,
+                home_dir,
+                login_shell,
+                group_list,
+                vals,
+            })
+        }
+        "group" => {
+            let groupname = vals.single("groupname")?;
+            let gid = vals.maybe_single("gid")?;
+            vals.ignore_common();
+            if !free.is_empty() {
+                bail!("spare arguments? {:?}", free);
+            }
+
+            vals.check_for_extra()?;
+
+            Action::Group(ActionGroup {
+                groupname,
+                gid,
+                vals,
+            })
+        }
+        "legacy" => {
+            let pkg = vals.single("pkg")?;
+            let name = vals.maybe_single("name")?;
+            let desc = vals.maybe_single("desc")?;
+            let version = vals.maybe_single("version")?;
+            let arch = vals.maybe_single("arch")?;
+            let category = vals.maybe_single("category")?;
+            let vendor = vals.maybe_single("vendor")?;
+            vals.ignore(&["hotline", "basedir", "pkginst", "pstamp"]);
+            vals.ignore_common();
+            if !free.is_empty() {
+                bail!("spare arguments? {:?}", free);
+            }
+
+            vals.check_for_extra()?;
+
+            Action::Legacy(ActionLegacy {
+                pkg,
+                name,
+                desc,
+                version,
+                arch,
+                category,
+                vendor,
+                vals,
+            })
+        }
+        "signature" => {
+            let value = vals.single("value")?;
+            let algorithm = vals.single("algorithm")?;
+            let chain = vals.maybe_list("chain");
+            if free.len() > 1 {
+                bail!("more than one hash? {:?}", free);
+            }
+            let hash = free.pop();
+            vals.ignore(&[
+                "version",
+                "chain.chashes",
+                "chain.csizes",
+                "chain.sizes",
+            ]);
+            vals.ignore_payload();
+            vals.ignore_common();
+
+            vals.check_for_extra()?;
+
+            Action::Signature(ActionSignature {
+                value,
+                algorithm,
+                hash,
+                chain,
+                vals,
+            })
+        }
+        _ => Action::Unknown(a.to_string(), free, vals),
+    })
 }
@@ -0,0 +1,93 @@
+/*
+ * Check the links between manual pages in the database.  A link should point
+ * at a page that is delivered as a file; one that points at nothing is
+ * dangling, and one that points at another link forms a chain.  Links that
+ * point into another section are legitimate, but are worth knowing about
+ * when sections move.
+ */
+
+use std::collections::BTreeSet;
+
+use super::{Database, Record};
+
+pub enum Problem {
+    /**
+     * A link that does not record its target; the database was built by an
+     * older version of "mkdb".
+     */
+    Unknown(Record),
+    Dangling(Record),
+    CrossSection(Record),
+    /**
+     * A link, followed by each page in the chain of links that it leads to.
+     * The last page is either a file, a page that does not exist, or one
+     * that we have already visited, if the chain is a loop.
+     */
+    Chain(Record, Vec<(String, String)>),
+}
+
+impl Problem {
+    /**
+     * Dangling links and loops are broken; everything else still works.
+     */
+    pub fn broken(&self, db: &Database) -> bool {
+        match self {
+            Problem::Dangling(_) => true,
+            Problem::Chain(_, chain) => {
+                let last = chain.last().unwrap();
+                db.lookup(&last.0, &last.1).is_none_or(|e| e.link)
+            }
+            _ => false,
+        }
+    }
+}
+
+pub fn check(db: &Database) -> Vec<Problem> {
+    let mut out = Vec::new();
+
+    for r in db.records().filter(|r| r.link) {
+        let (tsect, tpage) = if let Some(t) = &r.target {
+            t
+        } else {
+            out.push(Problem::Unknown(r.clone()));
+            continue;
+        };
+
+        if tsect != &r.sect {
+            out.push(Problem::CrossSection(r.clone()));
+        }
+
+        let t = if let Some(t) = db.lookup(tsect, tpage) {
+            t
+        } else {
+            out.push(Problem::Dangling(r.clone()));
+            continue;
+        };
+
+        if !t.link {
+            continue;
+        }
+
+        /*
+         * Follow the chain until it reaches a file, a page that does not
+         * exist, or a page we have seen before.
+         */
+        let mut seen = BTreeSet::new();
+        seen.insert((r.sect.to_string(), r.page.to_string()));
+        let mut chain = vec![(tsect.to_string(), tpage.to_string())];
+        let mut cur = t;
+        while let Some(next) = cur.target.clone() {
+            if !seen.insert((cur.sect.to_string(), cur.page.to_string())) {
+                break;
+            }
+            chain.push(next.clone());
+            match db.lookup(&next.0, &next.1) {
+                Some(n) if n.link => cur = n,
+                _ => break,
+            }
+        }
+        out.push(Problem::Chain(r.clone(), chain));
+    }
+
+    out
+}
@@ -1,19 +1,33 @@
-use std::cmp::Ordering;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Arc;
 
 use anyhow::{anyhow, bail, Context, Result};
-use lazy_static::lazy_static;
-use regex::Regex;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 mod command;
 use command::OutputExt;
 mod ips;
 use ips::*;
 mod contents;
+mod repo;
+mod catalog;
+mod mogrify;
+mod renumber;
+mod diff;
+mod p5m;
+mod makefiles;
+mod sources;
+mod roff;
+mod rewrite;
+mod xrefs;
+mod quirks;
+mod suggest;
+mod links;
+mod mapping;
+mod collisions;
 
 #[derive(Deserialize)]
 struct PkgRepoList {
@@ -40,10 +54,9 @@ pub fn pkgrepo_list(repo: &str, pattern: Option<&str>) -> Result<Vec<Package>> {
     }
 
     let list: Vec<PkgRepoList> = serde_json::from_slice(&res.stdout)?;
-    Ok(list
-        .iter()
+    list.iter()
         .map(|prl| Package::parse_fmri(&prl.pkg_fmri))
-        .collect::<Result<Vec<_>>>()?)
+        .collect::<Result<Vec<_>>>()
 }
 
 pub fn pkgrepo_contents(repo: &str, package: &Package) -> Result<Vec<Action>> {
@@ -53,7 +66,7 @@ pub fn pkgrepo_contents(repo: &str, package: &Package) -> Result<Vec<Action>> {
     cmd.arg("-m");
     cmd.arg("-s");
     cmd.arg(repo);
-    cmd.arg(&package.to_string());
+    cmd.arg(package.to_string());
 
     let res = cmd.output()?;
 
@@ -62,7 +75,64 @@ pub fn pkgrepo_contents(repo: &str, package: &Package) -> Result<Vec<Action>> {
     }
 
     let manifest = String::from_utf8(res.stdout)?;
-    Ok(parse_manifest(&manifest)?)
+    parse_manifest(&manifest)
+}
+
+/**
+ * A repository that is available as a local directory can be read directly,
+ * which does not require pkg(5) on the host.  Anything else (e.g., a URL) is
+ * handed to pkgrepo(1).  The caller opens a file repository once, with
+ * open_repo(), and passes it to each of these.
+ */
+pub fn open_repo(repo: &str) -> Result<Option<repo::Repository>> {
+    if repo::Repository::is_repository(repo) {
+        Ok(Some(repo::Repository::open(repo)?))
+    } else {
+        Ok(None)
+    }
+}
+
+pub fn repo_list(
+    repo: &str,
+    files: Option<&repo::Repository>,
+    pattern: Option<&str>,
+) -> Result<Vec<Package>> {
+    if let Some(files) = files {
+        files.list(pattern)
+    } else {
+        pkgrepo_list(repo, pattern)
+    }
+}
+
+pub fn repo_contents(
+    repo: &str,
+    files: Option<&repo::Repository>,
+    package: &Package,
+) -> Result<Vec<Action>> {
+    if let Some(files) = files {
+        files.contents(package)
+    } else {
+        pkgrepo_contents(repo, package)
+    }
+}
+
+/**
+ * Resolve the target of a link, relative to the directory that contains it.
+ * Returns None if the target would leave the root of the image.
+ */
+fn resolve_target(path: &str, target: &str) -> Option<String> {
+    let mut out = path.split('/').collect::<Vec<_>>();
+    out.pop();
+    for c in target.split('/') {
+        match c {
+            "." => {}
+            ".." => {
+                out.pop()?;
+            }
+            c => out.push(c),
+        }
+    }
+    Some(out.join("/"))
 }
 
 fn path_to_man(p: &str) -> Result<(String, String)> {
@@ -94,45 +164,77 @@ struct Record {
     sect: String,
     page: String,
     pkg: String,
-    orig_sect: Option<String>,
+    /**
+     * The publisher and version of the package, and the fileid (i.e., hash)
+     * of a file, where the database records them.
+     */
+    publisher: Option<String>,
+    version: Option<String>,
+    fileid: Option<String>,
+    /**
+     * The section and page this record had before transform(), if it moved.
+     */
+    orig: Option<(String, String)>,
+    /**
+     * The section and page that a link points to.
+     */
+    target: Option<(String, String)>,
 }
 
+/**
+ * The version of the database file format written by mkdb.  The first line of
+ * the file identifies the format and version, and is followed by "#key value"
+ * lines that describe where the records came from.  Each record then has nine
+ * columns: "f" or "l" for a file or a link, section, page, package name,
+ * publisher, package version, fileid, and the section and page of the target
+ * of a link.  Values that are not present are written as "-".
+ *
+ * The original format had no header, and only the first four columns, with
+ * the link target in two more columns on some versions.
+ */
+const DB_MAGIC: &str = "#futzman-database";
+const DB_VERSION: u32 = 2;
+
+/**
+ * The manual pages delivered by a set of packages, indexed by section and page
+ * name, with additional indexes by package and by page name alone.
+ */
 #[derive(Default)]
 struct Database {
-    records: Vec<Record>,
+    records: BTreeMap<String, BTreeMap<String, Record>>,
+    by_pkg: BTreeMap<String, BTreeSet<(String, String)>>,
+    by_page: BTreeMap<String, BTreeSet<String>>,
+    /**
+     * Records that could not be kept by transform() because another page
+     * already had the same section and name.
+     */
+    collisions: Vec<Record>,
+    /**
+     * The repository from which the database was made, and how the version
+     * of each package was chosen.
+     */
+    repo: Option<String>,
+    selection: Option<String>,
 }
 
 impl Database {
-    pub fn insert(
-        &mut self,
-        link: bool,
-        sect: &str,
-        page: &str,
-        pkg: &str,
-    ) -> Result<()> {
-        let nr = Record {
-            link,
-            sect: sect.to_string(),
-            page: page.to_string(),
-            pkg: pkg.to_string(),
-            orig_sect: None,
-        };
-
-        for r in &self.records {
-            if r.sect == nr.sect && r.page == nr.page {
-                bail!(
-                    "new record {:?} conflicts with existing record {:?}",
-                    nr,
-                    r
-                );
-            }
+    pub fn insert(&mut self, nr: Record) -> Result<()> {
+        if let Some(r) = self.lookup(&nr.sect, &nr.page) {
+            bail!("new record {:?} conflicts with existing record {:?}", nr, r);
         }
 
-        self.records.push(nr);
-        self.records.sort_by(|a, b| match a.sect.cmp(&b.sect) {
-            Ordering::Equal => a.page.cmp(&b.page),
-            x => x,
-        });
+        self.by_pkg
+            .entry(nr.pkg.to_string())
+            .or_default()
+            .insert((nr.sect.to_string(), nr.page.to_string()));
+        self.by_page
+            .entry(nr.page.to_string())
+            .or_default()
+            .insert(nr.sect.to_string());
+        self.records
+            .entry(nr.sect.to_string())
+            .or_default()
+            .insert(nr.page.to_string(), nr);
 
         Ok(())
     }
@@ -141,176 +243,353 @@ impl Database {
         let mut f = std::fs::File::open(path)?;
         let mut s = String::new();
         f.read_to_string(&mut s)?;
-        let mut records = Vec::new();
-        for l in s.lines() {
-            let t = l.split('\t').collect::<Vec<_>>();
-            if t.len() != 4 {
-                bail!("broken record {:?}", t);
+
+        let mut lines = s.lines().enumerate().peekable();
+        let version = match lines.peek() {
+            Some((_, l)) if l.starts_with(DB_MAGIC) => {
+                let v = l[DB_MAGIC.len()..].trim();
+                let v = v.parse::<u32>().map_err(|_| {
+                    anyhow!("{}: invalid version {:?}", path, v)
+                })?;
+                if v != DB_VERSION {
+                    bail!(
+                        "{}: database version {} is not supported (want {}); \
+                        run mkdb again",
+                        path,
+                        v,
+                        DB_VERSION
+                    );
+                }
+                lines.next();
+                v
+            }
+            _ => {
+                eprintln!(
+                    "WARNING: {} is in the original format, without a record \
+                    of where it came from; run mkdb again",
+                    path
+                );
+                1
             }
+        };
 
-            let link = match t[0] {
-                "l" => true,
-                "f" => false,
-                x => bail!("invalid link field {:?}", t),
-            };
+        let mut db = Database::default();
+        for (n, l) in lines {
+            if let Some(meta) = l.strip_prefix('#') {
+                if version == 1 {
+                    bail!("{} line {}: unexpected header", path, n + 1);
+                }
+                match meta.split_once('\t') {
+                    Some(("repo", v)) => db.repo = Some(v.to_string()),
+                    Some(("selection", v)) => {
+                        db.selection = Some(v.to_string())
+                    }
+                    _ => {
+                        /*
+                         * Ignore anything we do not understand, so that we
+                         * can add to the header without a new version.
+                         */
+                    }
+                }
+                continue;
+            }
 
-            records.push(Record {
-                link,
-                sect: t[1].to_string(),
-                page: t[2].to_string(),
-                pkg: t[3].to_string(),
-                orig_sect: None,
-            });
+            let r = if version == 1 {
+                Database::parse_v1(l)
+            } else {
+                Database::parse(l)
+            }
+            .with_context(|| anyhow!("{} line {}", path, n + 1))?;
+            db.insert(r)?;
         }
 
-        Ok(Database { records })
+        Ok(db)
     }
 
-    pub fn lookup(&self, sect: &str, page: &str) -> Option<Record> {
-        for r in self.records.iter() {
-            if r.page == page && r.sect == sect {
-                return Some(r.clone());
-            }
+    fn parse(l: &str) -> Result<Record> {
+        let t = l.split('\t').collect::<Vec<_>>();
+        if t.len() != 9 {
+            bail!("broken record {:?}", t);
         }
-        None
+
+        let link = match t[0] {
+            "l" => true,
+            "f" => false,
+            _ => bail!("invalid link field {:?}", t),
+        };
+
+        let opt = |v: &str| {
+            if v == "-" {
+                None
+            } else {
+                Some(v.to_string())
+            }
+        };
+        let target = match (opt(t[7]), opt(t[8])) {
+            (Some(ts), Some(tp)) if link => Some((ts, tp)),
+            (None, None) if !link => None,
+            _ => bail!("invalid link target {:?}", t),
+        };
+
+        Ok(Record {
+            link,
+            sect: t[1].to_string(),
+            page: t[2].to_string(),
+            pkg: t[3].to_string(),
+            publisher: opt(t[4]),
+            version: opt(t[5]),
+            fileid: opt(t[6]),
+            orig: None,
+            target,
+        })
     }
 
-    pub fn transform(&self) -> Database {
-        let mut out = Vec::new();
-
-        for r in self.records.iter() {
-            if r.sect == "1M" {
-                out.push(Record {
-                    link: r.link,
-                    sect: "8".to_string(),
-                    page: r.page.to_string(),
-                    pkg: r.pkg.to_string(),
-                    orig_sect: Some("1M".to_string()),
-                });
-                continue;
+    fn parse_v1(l: &str) -> Result<Record> {
+        let t = l.split('\t').collect::<Vec<_>>();
+        if t.len() != 4 && t.len() != 6 {
+            bail!("broken record {:?}", t);
+        }
+
+        let link = match t[0] {
+            "l" => true,
+            "f" => false,
+            _ => bail!("invalid link field {:?}", t),
+        };
+
+        /*
+         * Links may have the section and page of their target in two more
+         * columns.
+         */
+        let target = if t.len() == 6 {
+            if !link {
+                bail!("file record with a target {:?}", t);
             }
+            Some((t[4].to_string(), t[5].to_string()))
+        } else {
+            None
+        };
 
-            let mut i = r.sect.chars();
-            let s = i.next().unwrap();
-            let sub = i.collect::<String>();
+        Ok(Record {
+            link,
+            sect: t[1].to_string(),
+            page: t[2].to_string(),
+            pkg: t[3].to_string(),
+            publisher: None,
+            version: None,
+            fileid: None,
+            orig: None,
+            target,
+        })
+    }
 
-            let newsect = match s {
-                '4' => {
-                    format!("5{}", sub)
-                }
-                '5' => {
-                    format!("7{}", sub)
-                }
-                '7' => {
-                    format!("4{}", sub)
-                }
-                _ => {
-                    out.push(r.clone());
-                    continue;
-                }
-            };
+    /**
+     * Render the database in the current file format.
+     */
+    pub fn text(&self) -> String {
+        let mut out = format!("{}\t{}\n", DB_MAGIC, DB_VERSION);
+        if let Some(repo) = &self.repo {
+            out += &format!("#repo\t{}\n", repo);
+        }
+        if let Some(selection) = &self.selection {
+            out += &format!("#selection\t{}\n", selection);
+        }
 
-            out.push(Record {
-                link: r.link,
-                sect: newsect.to_string(),
-                orig_sect: Some(r.sect.to_string()),
-                page: r.page.to_string(),
-                pkg: r.pkg.to_string(),
-            });
+        for r in self.records() {
+            let opt =
+                |v: &Option<String>| v.as_deref().unwrap_or("-").to_string();
+            let (ts, tp) = match &r.target {
+                Some((ts, tp)) => (ts.as_str(), tp.as_str()),
+                None => ("-", "-"),
+            };
+            out += &format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                if r.link { "l" } else { "f" },
+                r.sect,
+                r.page,
+                r.pkg,
+                opt(&r.publisher),
+                opt(&r.version),
+                opt(&r.fileid),
+                ts,
+                tp
+            );
         }
 
-        out.sort_by(|a, b| match a.sect.cmp(&b.sect) {
-            Ordering::Equal => a.page.cmp(&b.page),
-            x => x,
-        });
+        out
+    }
 
-        Database { records: out }
+    /**
+     * Every record, in order of section and then page name.
+     */
+    pub fn records(&self) -> impl Iterator<Item = &Record> {
+        self.records.values().flat_map(|pages| pages.values())
     }
-}
 
-fn find_xrefs_line(l: &str) -> Result<Vec<(String, String)>> {
-    lazy_static! {
-        static ref RE2: Regex = Regex::new(
-            r#"(?x)
-            \\fB
-            \\fB
-            (?P<page>[a-zA-Z_0-9+.-]+)
-            \\fR
-            \\fR
-            \(
-            (?P<sect>[0-9][^\[)]*)
-            \)
-            "#
-        )
-        .unwrap();
-        static ref RE: Regex = Regex::new(
-            r#"(?x)
-            \\fB
-            (?P<page>[a-zA-Z_0-9+.-]+)
-            \\fR
-            \(
-            (?P<sect>[0-9][^\[)]*)
-            \)
-            "#
-        )
-        .unwrap();
+    pub fn lookup(&self, sect: &str, page: &str) -> Option<&Record> {
+        self.records.get(sect)?.get(page)
     }
 
-    let mut out = Vec::new();
+    /**
+     * The records for pages delivered by this package.
+     */
+    pub fn package<'a>(
+        &'a self,
+        pkg: &str,
+    ) -> impl Iterator<Item = &'a Record> + 'a {
+        self.by_pkg
+            .get(pkg)
+            .into_iter()
+            .flatten()
+            .filter_map(|(sect, page)| self.lookup(sect, page))
+    }
 
-    for m in RE2.captures_iter(l) {
-        let m: regex::Captures = m;
-        let page = m.name("page").unwrap().as_str();
-        let sect = m.name("sect").unwrap().as_str();
-        // if page.starts_with("-") {
-        //     /*
-        //      * Probably a poorly typeset command-line argument.
-        //      */
-        //     continue;
-        // }
-        if sect.contains(r#"\fI"#) {
+    /**
+     * The records for pages with this name, in any section.
+     */
+    pub fn named<'a>(
+        &'a self,
+        page: &'a str,
+    ) -> impl Iterator<Item = &'a Record> + 'a {
+        self.by_page
+            .get(page)
+            .into_iter()
+            .flatten()
+            .filter_map(move |sect| self.lookup(sect, page))
+    }
+
+    /**
+     * Produce the database as it will be once the pages are moved according
+     * to this mapping.
+     */
+    pub fn transform(&self, map: &mapping::Mapping) -> Database {
+        let mut moved = Vec::new();
+        let mut stayed = Vec::new();
+
+        for r in self.records() {
             /*
-             * This is probably a poorly typeset function call.
+             * Links move according to their own section, but their targets
+             * may be in a different one.
              */
-            continue;
+            let mut r = r.clone();
+            if let Some((ts, tp)) = r.target.take() {
+                r.target = Some(map.page(&ts, &tp).unwrap_or((ts, tp)));
+            }
+
+            if let Some((ns, np)) = map.page(&r.sect, &r.page) {
+                let sect = std::mem::replace(&mut r.sect, ns);
+                let page = std::mem::replace(&mut r.page, np);
+                r.orig = Some((sect, page));
+                moved.push(r);
+            } else {
+                stayed.push(r);
+            }
         }
-        if sect != sect.to_uppercase() {
-            eprintln!("sect {:?} is not in uppercase: {:?}", sect, l);
-            continue;
+
+        /*
+         * A page that moves into a section obscures any page of the same
+         * name that was already there.
+         */
+        let mut out = Database::default();
+        for r in moved.into_iter().chain(stayed) {
+            if out.lookup(&r.sect, &r.page).is_some() {
+                out.collisions.push(r);
+            } else {
+                out.insert(r).unwrap();
+            }
         }
 
-        out.push((sect.to_string(), page.to_string()));
+        out
     }
+}
 
-    for m in RE.captures_iter(l) {
-        let m: regex::Captures = m;
-        let page = m.name("page").unwrap().as_str();
-        let sect = m.name("sect").unwrap().as_str();
-        // if page.starts_with("-") {
-        //     /*
-        //      * Probably a poorly typeset command-line argument.
-        //      */
-        //     continue;
-        // }
-        if sect.contains(r#"\fI"#) {
-            /*
-             * This is probably a poorly typeset function call.
-             */
-            continue;
-        }
-        if sect != sect.to_uppercase() {
-            eprintln!("sect {:?} is not in uppercase: {:?}", sect, l);
-            continue;
+/**
+ * Load the section mapping named with -m, or the one in "mapping.txt" if
+ * there is one; otherwise, use the built-in renumbering.
+ */
+fn load_mapping(mat: &getopts::Matches) -> Result<mapping::Mapping> {
+    match mat.opt_str("m") {
+        Some(m) => mapping::Mapping::load(m),
+        None if Path::new("mapping.txt").exists() => {
+            mapping::Mapping::load("mapping.txt")
         }
+        None => Ok(Default::default()),
+    }
+}
+
+/**
+ * A cross-reference, with the position of the page name in the source, the
+ * heading of the section (.SH or .Sh) in which it appears, and the number of
+ * times the same page is referenced in the whole file.
+ */
+#[derive(Debug, Clone, Serialize)]
+struct Xref {
+    sect: String,
+    page: String,
+    line: usize,
+    col: usize,
+    heading: Option<String>,
+    count: usize,
+}
+
+/**
+ * The text of a section heading request; e.g., "SEE ALSO" for both
+ * '.SH "SEE ALSO"' and ".Sh SEE ALSO".
+ */
+fn heading(l: &str) -> Option<String> {
+    let rest = l
+        .strip_prefix(".SH")
+        .or_else(|| l.strip_prefix(".Sh"))?;
+    if !rest.is_empty() && !rest.starts_with([' ', '\t']) {
+        return None;
+    }
+
+    let words = roff::arguments(roff::strip_comment(rest))
+        .into_iter()
+        .map(|a| a.2)
+        .collect::<Vec<_>>();
+    Some(words.join(" "))
+}
 
-        out.push((sect.to_string(), page.to_string()));
+/**
+ * Fill in the heading and occurrence count for each reference, given the
+ * line number and text of each heading in the file.
+ */
+fn annotate(
+    refs: Vec<(String, String, usize, usize)>,
+    headings: &[(usize, String)],
+) -> Vec<Xref> {
+    let mut counts: BTreeMap<(String, String), usize> = BTreeMap::new();
+    for (sect, page, _, _) in refs.iter() {
+        *counts.entry((sect.to_string(), page.to_string())).or_default() += 1;
     }
 
-    Ok(out)
+    refs.into_iter()
+        .map(|(sect, page, line, col)| Xref {
+            heading: headings
+                .iter()
+                .rev()
+                .find(|(n, _)| *n <= line)
+                .map(|(_, h)| h.to_string()),
+            count: counts[&(sect.to_string(), page.to_string())],
+            sect,
+            page,
+            line,
+            col,
+        })
+        .collect()
 }
 
-fn find_xrefs(content: &str) -> Result<Vec<(String, String)>> {
-    #[derive(Debug)]
+/**
+ * Find cross-references in a man(7) page.  Anything before the ".TH" header
+ * should be a block of comments; a page that does not look like that is
+ * still scanned, and the returned list of (line, message) notes each
+ * fallback that was needed.
+ */
+fn find_xrefs(
+    content: &str,
+    quirks: &quirks::Quirks,
+) -> (Vec<Xref>, Vec<(usize, String)>) {
+    #[derive(Debug, PartialEq)]
     enum State {
         Rest,
         Copyright,
@@ -320,17 +599,15 @@ fn find_xrefs(content: &str) -> Result<Vec<(String, String)>> {
     }
 
     let mut st = State::Rest;
-    let mut out = Vec::new();
+    let mut text = roff::Text::default();
+    let mut headings = Vec::new();
+    let mut fallbacks = Vec::new();
+    let mut skipped: Vec<usize> = Vec::new();
 
-    for l in content.lines() {
+    for (n, l) in content.lines().enumerate() {
         match st {
             State::Rest => {
-                if l.starts_with(".TH WHOIS")
-                    || l.starts_with(".TH HOSTS_ACCESS")
-                {
-                    /*
-                     * WTF.
-                     */
+                if quirks.bare_title(l) {
                     st = State::Content;
                     continue;
                 }
@@ -342,14 +619,22 @@ fn find_xrefs(content: &str) -> Result<Vec<(String, String)>> {
                     || l == r#".\" -*- tab-width: 4 -*-"#
                     || l == r#".\" -*- nroff -*-"#
                     || (l.starts_with(r#".\""#) && l.contains("Copyright"))
+                    || quirks.preamble(l)
                 {
                     st = State::Copyright;
+                } else if l.starts_with(".TH") {
+                    fallbacks.push((n + 1, "no preamble before .TH".into()));
+                    st = State::Content;
                 } else {
-                    bail!("what? {:?}? {:?}", st, l);
+                    fallbacks.push((
+                        n + 1,
+                        format!("unexpected start of preamble {:?}", l),
+                    ));
+                    st = State::Copyright;
                 }
             }
             State::Copyright => {
-                if l.starts_with(r#".\""#) || l == "" {
+                if l.starts_with(r#".\""#) || l.is_empty() {
                     continue;
                 } else if l.starts_with(".TH") {
                     /*
@@ -357,18 +642,14 @@ fn find_xrefs(content: &str) -> Result<Vec<(String, String)>> {
                      */
                     st = State::Content;
                 } else {
-                    bail!("what? {:?}? {:?}", st, l);
+                    skipped.push(n + 1);
                 }
             }
             State::Content => {
-                if l.starts_with(".") {
-                    /*
-                     * XXX
-                     */
-                    continue;
+                if let Some(h) = heading(l) {
+                    headings.push((n + 1, h));
                 }
-
-                out.extend(find_xrefs_line(l)?);
+                text.line(n + 1, l);
             }
             //     if l == ".SH NAME" {
             //         st = State::Name;
@@ -385,13 +666,121 @@ fn find_xrefs(content: &str) -> Result<Vec<(String, String)>> {
             //         bail!("what? {:?}? {:?}", st, l);
             //     }
             // }
-            _ => {
-                bail!("what? {:?}? {:?}", st, l);
+        }
+    }
+
+    if st != State::Content {
+        /*
+         * Without a header we cannot tell where the preamble ends, so scan
+         * the whole page.
+         */
+        fallbacks.push((1, "no .TH header; scanned the whole page".into()));
+        text = roff::Text::default();
+        headings.clear();
+        for (n, l) in content.lines().enumerate() {
+            if let Some(h) = heading(l) {
+                headings.push((n + 1, h));
             }
+            text.line(n + 1, l);
         }
+    } else if let Some(first) = skipped.first() {
+        fallbacks.push((
+            *first,
+            format!(
+                "ignored {} unexpected line{} in preamble",
+                skipped.len(),
+                if skipped.len() == 1 { "" } else { "s" }
+            ),
+        ));
     }
 
-    Ok(out)
+    let mut out = Vec::new();
+    for r in roff::references(text.chars()) {
+        if r.sect != r.sect.to_uppercase() {
+            eprintln!(
+                "sect {:?} is not in uppercase: line {}, column {}",
+                r.sect, r.line, r.col
+            );
+            continue;
+        }
+
+        out.push((r.sect, r.page, r.line, r.col));
+    }
+
+    (annotate(out, &headings), fallbacks)
+}
+
+/**
+ * Find cross-references in an mdoc(7) page.  These are made with the "Xr"
+ * macro, which may be the request itself or called later on the line; e.g.,
+ * ".Xr passwd 4 ," or ".Pq Xr sd 7D".  Trailing punctuation is usually a
+ * separate argument, but is sometimes stuck to the section.
+ */
+fn find_xrefs_mdoc(content: &str) -> Vec<Xref> {
+    let mut out = Vec::new();
+    let mut headings = Vec::new();
+
+    for (n, l) in content.lines().enumerate() {
+        if let Some(h) = heading(l) {
+            headings.push((n + 1, h));
+        }
+        if l.starts_with(r#".\""#) {
+            continue;
+        }
+        let args = if let Some(req) = l.strip_prefix('.') {
+            roff::arguments(req)
+        } else {
+            continue;
+        };
+
+        for (i, a) in args.iter().enumerate() {
+            if a.2 != "Xr" {
+                continue;
+            }
+
+            let (page, sect) = match (args.get(i + 1), args.get(i + 2)) {
+                (Some(page), Some(sect)) => (page, &sect.2),
+                _ => {
+                    eprintln!("Xr without a section: {:?}", l);
+                    continue;
+                }
+            };
+            let sect = sect.trim_end_matches(|c| ".,:;)]?!".contains(c));
+            if !sect.starts_with(|c: char| c.is_ascii_digit()) {
+                eprintln!("Xr with peculiar section {:?}: {:?}", sect, l);
+                continue;
+            }
+
+            /*
+             * Columns count from one, and the arguments begin after the
+             * control character.
+             */
+            out.push((
+                sect.to_uppercase(),
+                page.2.to_string(),
+                n + 1,
+                page.0 + 2,
+            ));
+        }
+    }
+
+    annotate(out, &headings)
+}
+
+/**
+ * Quote a string for use as a single word in a shell script, if it needs it.
+ */
+fn shell_quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars().all(|c| {
+            c.is_ascii_alphanumeric()
+                || matches!(c, '.' | '_' | '-' | '+' | '/')
+        })
+    {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
 }
 
 fn main() -> Result<()> {
@@ -399,19 +788,52 @@ fn main() -> Result<()> {
 
     match cmd.as_str() {
         "kinds" => {
+            let mut opts = getopts::Options::new();
+            opts.optflag("j", "", "report each cross-reference as JSON");
+            opts.optopt("q", "", "pages with unusual preambles", "QUIRKS");
+            opts.optopt("f", "", "write suggested fixes to this file", "FIXES");
+            opts.optopt("p", "", "check only pages from this package", "PKG");
+            opts.optopt("m", "", "section mapping", "MAPPING");
+            let mat = opts.parse(std::env::args().skip(2))?;
+            let json = mat.opt_present("j");
+            let map = load_mapping(&mat)?;
+
+            let quirks = match mat.opt_str("q") {
+                Some(q) => quirks::Quirks::load(q)?,
+                None if Path::new("quirks.txt").exists() => {
+                    quirks::Quirks::load("quirks.txt")?
+                }
+                None => Default::default(),
+            };
+
             let db = Database::load("database.txt")?;
+            let mut fallbacks: Vec<(PathBuf, Vec<String>)> = Vec::new();
+            let mut fixes: BTreeMap<(String, String, String), String> =
+                BTreeMap::new();
+
+            let pages = match mat.opt_str("p") {
+                Some(pkg) => {
+                    let pages = db.package(&pkg).collect::<Vec<_>>();
+                    if pages.is_empty() {
+                        bail!("package {:?} delivers no pages", pkg);
+                    }
+                    pages
+                }
+                None => db.records().collect(),
+            };
 
-            for r in db.records.iter() {
+            for r in pages {
                 if r.link {
                     continue;
                 }
 
-                let p = PathBuf::from(format!(
-                    "/ws/rti/usr/src/man/man{}/{}.{}",
+                let file = format!(
+                    "man{}/{}.{}",
                     r.sect.to_ascii_lowercase(),
                     r.page,
                     r.sect.to_ascii_lowercase()
-                ));
+                );
+                let p = Path::new("/ws/rti/usr/src/man").join(&file);
 
                 if !p.exists() && r.page.contains("event") && r.sect == "3CPC" {
                     /*
@@ -423,45 +845,139 @@ fn main() -> Result<()> {
                 /*
                  * Do we think this is mandoc or not?
                  */
-                let mut f = std::fs::File::open(&p)?;
-                let mut s = String::new();
-                f.read_to_string(&mut s)?;
-                if s.lines()
-                    .any(|l| l == ".Os" || l.starts_with(".Os illumos"))
-                {
-                    println!("mdoc {}({})", r.page, r.sect);
-                    continue;
+                let s = match std::fs::read_to_string(&p) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        eprintln!("{}: could not read: {}", p.display(), e);
+                        fallbacks.push((p, vec![format!("not read: {}", e)]));
+                        continue;
+                    }
+                };
+                let mdoc = s
+                    .lines()
+                    .any(|l| l == ".Os" || l.starts_with(".Os illumos"));
+                if !json {
+                    let kind = if mdoc { "mdoc" } else { "roff" };
+                    println!("{} {}({})", kind, r.page, r.sect);
+                }
+                let xrefs = if mdoc {
+                    find_xrefs_mdoc(&s)
                 } else {
-                    println!("roff {}({})", r.page, r.sect);
-                    let xrefs = find_xrefs(&s)
-                        .with_context(|| anyhow!("file {:?}", p))?;
-                    for xref in &xrefs {
-                        //println!("{:?}", xref);
-                        if db.lookup(&xref.0, &xref.1).is_none() {
-                            eprintln!("MISSING {}({})?", xref.1, xref.0);
-                        } else {
-                            println!("    -> {}({})", xref.1, xref.0);
+                    let (xrefs, fb) = find_xrefs(&s, &quirks);
+                    for (line, msg) in fb.iter() {
+                        eprintln!("{}:{}: warning: {}", p.display(), line, msg);
+                    }
+                    if !fb.is_empty() {
+                        fallbacks.push((
+                            p.clone(),
+                            fb.into_iter().map(|(_, msg)| msg).collect(),
+                        ));
+                    }
+                    xrefs
+                };
+
+                for xref in &xrefs {
+                    //println!("{:?}", xref);
+                    let missing = db.lookup(&xref.sect, &xref.page).is_none();
+                    let suggestions = if missing {
+                        suggest::suggest(&db, &map, &xref.sect, &xref.page)
+                    } else {
+                        Vec::new()
+                    };
+                    if missing {
+                        let key = (
+                            file.to_string(),
+                            xref.page.to_string(),
+                            xref.sect.to_string(),
+                        );
+                        if let Some(l) = suggest::fix_line(
+                            &file,
+                            &xref.page,
+                            &xref.sect,
+                            &suggestions,
+                        ) {
+                            fixes.insert(key, l);
                         }
                     }
+
+                    if json {
+                        let mut v = serde_json::to_value(xref)?;
+                        v["file"] = p.to_string_lossy().into();
+                        v["missing"] = missing.into();
+                        v["suggestions"] = serde_json::to_value(&suggestions)?;
+                        println!("{}", v);
+                    } else if missing {
+                        /*
+                         * Report the position in a form that editors can
+                         * jump to.
+                         */
+                        eprintln!(
+                            "{}:{}:{}: MISSING {}({})? (in {}; {} {})",
+                            p.display(),
+                            xref.line,
+                            xref.col,
+                            xref.page,
+                            xref.sect,
+                            xref.heading.as_deref().unwrap_or("preamble"),
+                            xref.count,
+                            if xref.count == 1 {
+                                "occurrence"
+                            } else {
+                                "occurrences"
+                            }
+                        );
+                        for sg in suggestions.iter() {
+                            eprintln!(
+                                "    did you mean {}({})? ({})",
+                                sg.page, sg.sect, sg.why
+                            );
+                        }
+                    } else {
+                        println!("    -> {}({})", xref.page, xref.sect);
+                    }
+                }
+            }
+
+            if let Some(path) = mat.opt_str("f") {
+                let mut out = String::new();
+                for l in fixes.values() {
+                    out += l;
+                    out.push('\n');
+                }
+                std::fs::write(&path, out)
+                    .with_context(|| anyhow!("writing {:?}", path))?;
+                eprintln!("wrote {} fixes to {:?}", fixes.len(), path);
+            }
+
+            if !fallbacks.is_empty() {
+                eprintln!();
+                eprintln!(
+                    "{} page{} needed fallbacks:",
+                    fallbacks.len(),
+                    if fallbacks.len() == 1 { "" } else { "s" }
+                );
+                for (p, msgs) in fallbacks.iter() {
+                    eprintln!("    {}: {}", p.display(), msgs.join("; "));
                 }
             }
         }
         "conflicts" => {
+            let mut opts = getopts::Options::new();
+            opts.optopt("m", "", "section mapping", "MAPPING");
+            let mat = opts.parse(std::env::args().skip(2))?;
+            let map = load_mapping(&mat)?;
+
             let db = Database::load("database.txt")?;
 
             let mut conflicts: BTreeMap<String, Vec<String>> = BTreeMap::new();
 
-            for r in db.records.iter() {
-                match r.sect.chars().next().unwrap() {
-                    '4' | '5' | '7' => {
-                        let c =
-                            conflicts.entry(r.page.to_string()).or_default();
-                        if !c.contains(&r.sect) {
-                            c.push(r.sect.to_string());
-                        }
-                        //println!("{}", r.page);
+            for r in db.records() {
+                if map.touches(&r.sect) {
+                    let c = conflicts.entry(r.page.to_string()).or_default();
+                    if !c.contains(&r.sect) {
+                        c.push(r.sect.to_string());
                     }
-                    _ => {}
+                    //println!("{}", r.page);
                 }
             }
 
@@ -481,33 +997,674 @@ fn main() -> Result<()> {
             //println!("{:#?}", conflicts);
         }
         "simulate" => {
+            let mut opts = getopts::Options::new();
+            opts.optopt("m", "", "section mapping", "MAPPING");
+            let mat = opts.parse(std::env::args().skip(2))?;
+            let map = load_mapping(&mat)?;
+
             let db = Database::load("database.txt")?;
-            let newdb = db.transform();
+            let newdb = db.transform(&map);
 
-            for r in db.records {
+            for r in db.records() {
                 if let Some(conflict) = newdb.lookup(&r.sect, &r.page) {
-                    if conflict.orig_sect.is_none() {
+                    if conflict.orig.is_none() {
                         continue;
                     }
                     println!("old page {}({}) is obscured", r.page, r.sect);
                 }
             }
         }
+        "collisions" => {
+            /*
+             * Report every page that would collide with another once the
+             * mapping is applied.
+             */
+            let mut opts = getopts::Options::new();
+            opts.optopt("m", "", "section mapping", "MAPPING");
+            let mat = opts.parse(std::env::args().skip(2))?;
+            let map = load_mapping(&mat)?;
+
+            let db = Database::load("database.txt")?;
+
+            let found = collisions::analyse(&db, &map);
+            for c in found.iter() {
+                let (sect, page) = &c.sides[0].new;
+                match c.kind {
+                    collisions::Kind::Page => {
+                        println!("COLLISION {}({})", page, sect);
+                    }
+                    collisions::Kind::LinkFile => {
+                        println!("LINK/FILE COLLISION {}({})", page, sect);
+                    }
+                    collisions::Kind::Subsection => {
+                        println!(
+                            "SUBSECTION CLASH {}({})",
+                            page,
+                            mapping::main_section(sect)
+                        );
+                    }
+                }
+
+                for s in c.sides.iter() {
+                    let (os, op) = &s.old;
+                    let (ns, np) = &s.new;
+                    let moved = if s.moved() {
+                        format!("-> {}({})", np, ns)
+                    } else {
+                        "(stays)".to_string()
+                    };
+                    println!(
+                        "    {:<4} {:<24} {:<24} {}",
+                        if s.link { "link" } else { "file" },
+                        format!("{}({})", op, os),
+                        moved,
+                        s.pkg
+                    );
+                }
+            }
+
+            if !found.is_empty() {
+                bail!(
+                    "{} collision{}",
+                    found.len(),
+                    if found.len() == 1 { "" } else { "s" }
+                );
+            }
+        }
+        "manifest" => {
+            /*
+             * Render a manifest file in canonical form.  We check that the
+             * output parses back to the same thing, as any difference would
+             * be a bug in either the parser or the serialiser.
+             */
+            let mut opts = getopts::Options::new();
+            opts.optmulti(
+                "V",
+                "",
+                "include only actions for this variant value",
+                "NAME=VALUE",
+            );
+            let mat = opts.parse(std::env::args().skip(2))?;
+            let vf = VariantFilter::parse(&mat.opt_strs("V"))?;
+
+            let path = if let [path] = mat.free.as_slice() {
+                path
+            } else {
+                bail!("usage: manifest [-V NAME=VALUE]... FILE");
+            };
+
+            let input = std::fs::read_to_string(path)?;
+            let actions = vf.filter(
+                &parse_manifest(&input)
+                    .with_context(|| anyhow!("file {:?}", path))?,
+            );
+            let out = format_manifest(&actions);
+            let again = format_manifest(&parse_manifest(&out)?);
+            if out != again {
+                bail!("manifest {:?} does not round trip", path);
+            }
+
+            print!("{}", out);
+        }
+        "mogrify" => {
+            /*
+             * Apply pkgmogrify(1) transforms to a set of manifests, and
+             * report the actions that they change.
+             */
+            let mut opts = getopts::Options::new();
+            opts.optmulti("I", "", "search for include files here", "DIR");
+            opts.optmulti("D", "", "define a macro", "NAME=VALUE");
+            opts.optflag("O", "", "print the whole transformed manifest");
+            let mat = opts.parse(std::env::args().skip(2))?;
+            if mat.free.is_empty() {
+                bail!("usage: mogrify [-O] [-I DIR]... [-D NAME=VALUE]... FILE...");
+            }
+
+            let mut m = mogrify::Mogrify::new();
+            for dir in mat.opt_strs("I") {
+                m.include_dir(dir);
+            }
+            for d in mat.opt_strs("D") {
+                if let Some((k, v)) = d.split_once('=') {
+                    m.define(k, v);
+                } else {
+                    bail!("macro {:?} should be of the form NAME=VALUE", d);
+                }
+            }
+
+            /*
+             * As with pkgmogrify, transforms loaded from any file apply to
+             * the actions from every file.
+             */
+            let mut actions = Vec::new();
+            for f in mat.free.iter() {
+                actions.extend(m.load(f)?);
+            }
+
+            let oc = m.apply(&actions)?;
+
+            for p in oc.printed.iter() {
+                eprintln!("{}", p);
+            }
+
+            if mat.opt_present("O") {
+                print!("{}", format_manifest(&oc.actions()));
+            } else {
+                let mut changed = 0;
+                for a in oc.applied.iter().filter(|a| a.changed()) {
+                    changed += 1;
+                    println!("- {}", a.input);
+                    for o in a.output.iter() {
+                        println!("+ {}", o);
+                    }
+                }
+                eprintln!(
+                    "{} of {} actions changed",
+                    changed,
+                    oc.applied.len()
+                );
+            }
+
+            if let Some((code, msg)) = oc.exit {
+                if let Some(msg) = msg {
+                    eprintln!("{}", msg);
+                }
+                std::process::exit(code);
+            }
+        }
+        "catalog" => {
+            let repo = std::env::args()
+                .nth(2)
+                .ok_or_else(|| anyhow!("usage: catalog REPO"))?;
+
+            for cat in repo::Repository::open(&repo)?.catalogs()? {
+                for ce in cat.entries() {
+                    let flags = if ce.obsolete() {
+                        "o"
+                    } else if ce.renamed() {
+                        "r"
+                    } else {
+                        "-"
+                    };
+                    let sig = ce
+                        .signatures()
+                        .get("sha-1")
+                        .map(String::as_str)
+                        .unwrap_or("-");
+                    println!(
+                        "{}\t{}\t{}\t{}",
+                        flags,
+                        ce.pkg(),
+                        sig,
+                        ce.summary().unwrap_or("")
+                    );
+                }
+            }
+        }
+        "mogrules" => {
+            /*
+             * Emit pkgmogrify transforms that perform the renumbering, having
+             * first checked that they reproduce the transformed database.
+             */
+            let mut opts = getopts::Options::new();
+            opts.optopt("m", "", "section mapping", "MAPPING");
+            let mat = opts.parse(std::env::args().skip(2))?;
+            let map = load_mapping(&mat)?;
+            let db = Database::load("database.txt")?;
+
+            let rules = renumber::transforms(&db, &map);
+            let problems = renumber::verify(&db, &map, &rules)?;
+            if !problems.is_empty() {
+                for p in problems.iter() {
+                    eprintln!("PROBLEM {}", p);
+                }
+                bail!("generated transforms do not match the database");
+            }
+
+            print!("{}", rules);
+        }
+        "p5m" => {
+            /*
+             * Rewrite illumos-gate packaging manifests so that they deliver
+             * manual pages into their new sections.  By default we print a
+             * diff for review; with -w the files are updated in place.
+             */
+            let mut opts = getopts::Options::new();
+            opts.optflag("w", "", "rewrite the manifests in place");
+            opts.optopt("m", "", "section mapping", "MAPPING");
+            let mat = opts.parse(std::env::args().skip(2))?;
+            if mat.free.is_empty() {
+                bail!("usage: p5m [-w] [-m MAPPING] FILE|DIR...");
+            }
+            let map = load_mapping(&mat)?;
+
+            /*
+             * A directory is taken to be usr/src/pkg/manifests, or similar.
+             */
+            let mut files = Vec::new();
+            for f in mat.free.iter() {
+                let p = PathBuf::from(f);
+                if p.is_dir() {
+                    let mut ents = std::fs::read_dir(&p)
+                        .with_context(|| anyhow!("reading {:?}", p))?
+                        .map(|ent| Ok(ent?.path()))
+                        .collect::<Result<Vec<_>>>()?;
+                    ents.retain(|p| {
+                        p.extension().is_some_and(|e| e == "p5m")
+                    });
+                    ents.sort();
+                    files.extend(ents);
+                } else {
+                    files.push(p);
+                }
+            }
+
+            let mut changed = 0;
+            for f in files.iter() {
+                let input = std::fs::read_to_string(f)
+                    .with_context(|| anyhow!("reading {:?}", f))?;
+                let rw = p5m::rewrite(&map, &input);
+                for w in rw.warnings.iter() {
+                    eprintln!("WARNING {}: {}", f.display(), w);
+                }
+                if !rw.changed() {
+                    continue;
+                }
+                changed += 1;
+
+                if mat.opt_present("w") {
+                    std::fs::write(f, rw.text())
+                        .with_context(|| anyhow!("writing {:?}", f))?;
+                } else {
+                    let name = f.to_string_lossy();
+                    print!(
+                        "{}",
+                        diff::unified(
+                            name.trim_start_matches('/'),
+                            &rw.before,
+                            &rw.after
+                        )
+                    );
+                }
+            }
+            eprintln!("{} of {} manifests changed", changed, files.len());
+        }
+        "makefiles" => {
+            /*
+             * Move the entries for each page between the Makefiles under
+             * usr/src/man.  By default we print a diff for review; with -w
+             * the Makefiles are created, updated and removed in place.
+             */
+            let mut opts = getopts::Options::new();
+            opts.optflag("w", "", "update the Makefiles in place");
+            opts.optopt("m", "", "section mapping", "MAPPING");
+            let mat = opts.parse(std::env::args().skip(2))?;
+            let mandir = match mat.free.as_slice() {
+                [] => "/ws/rti/usr/src/man",
+                [mandir] => mandir.as_str(),
+                _ => bail!("usage: makefiles [-w] [-m MAPPING] [MANDIR]"),
+            };
+            let map = load_mapping(&mat)?;
+
+            let plan = makefiles::plan(&map, std::path::Path::new(mandir))?;
+            for w in plan.warnings.iter() {
+                eprintln!("WARNING {}", w);
+            }
+
+            for c in plan.changes.iter() {
+                if mat.opt_present("w") {
+                    if let Some(after) = &c.after {
+                        if let Some(dir) = c.path.parent() {
+                            std::fs::create_dir_all(dir)?;
+                        }
+                        std::fs::write(&c.path, after.join("\n") + "\n")
+                            .with_context(|| anyhow!("writing {:?}", c.path))?;
+                    } else {
+                        std::fs::remove_file(&c.path)
+                            .with_context(|| anyhow!("removing {:?}", c.path))?;
+                    }
+                    continue;
+                }
+
+                let name = c.path.to_string_lossy();
+                let name = name.trim_start_matches('/');
+                print!(
+                    "{}",
+                    match (&c.before, &c.after) {
+                        (Some(b), Some(a)) => diff::unified(name, b, a),
+                        (None, Some(a)) => diff::created(name, a),
+                        (Some(b), None) => diff::removed(name, b),
+                        (None, None) => String::new(),
+                    }
+                );
+            }
+            eprintln!("{} Makefiles changed", plan.changes.len());
+        }
+        "sources" => {
+            /*
+             * Move the source for each manual page to its new section, and
+             * rewrite the section in its header.  By default we print a shell
+             * script that does this with "git mv" and patch(1), which can be
+             * reviewed before it is run; with -w the changes are made in
+             * place.
+             */
+            let mut opts = getopts::Options::new();
+            opts.optflag("w", "", "move and rewrite the pages in place");
+            opts.optopt("m", "", "section mapping", "MAPPING");
+            let mat = opts.parse(std::env::args().skip(2))?;
+            let mandir = match mat.free.as_slice() {
+                [] => "/ws/rti/usr/src/man",
+                [mandir] => mandir.as_str(),
+                _ => bail!("usage: sources [-w] [-m MAPPING] [MANDIR]"),
+            };
+            let mandir = std::path::Path::new(mandir);
+            let map = load_mapping(&mat)?;
+
+            let plan = sources::plan(&map, mandir)?;
+            for w in plan.warnings.iter() {
+                eprintln!("WARNING {}", w);
+            }
+            let renames = sources::order(&plan.moves);
+
+            if mat.opt_present("w") {
+                for (from, to) in renames.iter() {
+                    if let Some(dir) = to.parent() {
+                        std::fs::create_dir_all(dir)?;
+                    }
+                    std::fs::rename(from, to).with_context(|| {
+                        anyhow!("renaming {:?} to {:?}", from, to)
+                    })?;
+                }
+                for m in plan.moves.iter().filter(|m| m.page.changed()) {
+                    std::fs::write(&m.to, m.page.text())
+                        .with_context(|| anyhow!("writing {:?}", m.to))?;
+                }
+            } else {
+                let rel = |p: &std::path::Path| {
+                    shell_quote(
+                        &p.strip_prefix(mandir).unwrap_or(p).to_string_lossy(),
+                    )
+                };
+
+                println!("#!/bin/sh");
+                println!("#");
+                println!("# Move manual pages to their new sections.");
+                println!("# Generated by futzman; review before running.");
+                println!("#");
+                println!("set -o errexit");
+                println!("cd {}", shell_quote(&mandir.to_string_lossy()));
+
+                let mut dirs = plan
+                    .moves
+                    .iter()
+                    .filter_map(|m| m.to.parent())
+                    .collect::<Vec<_>>();
+                dirs.sort();
+                dirs.dedup();
+                for d in dirs {
+                    println!("mkdir -p {}", rel(d));
+                }
+                for (from, to) in renames.iter() {
+                    println!("git mv {} {}", rel(from), rel(to));
+                }
+
+                println!("patch -p1 <<'EOF'");
+                for m in plan.moves.iter() {
+                    let name = m.to.strip_prefix(mandir).unwrap_or(&m.to);
+                    print!(
+                        "{}",
+                        diff::unified(
+                            &name.to_string_lossy(),
+                            &m.page.before,
+                            &m.page.after
+                        )
+                    );
+                }
+                println!("EOF");
+            }
+            eprintln!("{} pages moved", plan.moves.len());
+        }
+        "xrefs" => {
+            /*
+             * Rewrite cross-references to pages that are moving.  Only
+             * references to pages that appear in the database are rewritten;
+             * any others that name a moving section are reported.
+             */
+            let mut opts = getopts::Options::new();
+            opts.optflag("w", "", "rewrite the pages in place");
+            opts.optopt("f", "", "apply fixes from this file", "FIXES");
+            opts.optopt("m", "", "section mapping", "MAPPING");
+            let mat = opts.parse(std::env::args().skip(2))?;
+            let mandir = match mat.free.as_slice() {
+                [] => "/ws/rti/usr/src/man",
+                [mandir] => mandir.as_str(),
+                _ => {
+                    bail!("usage: xrefs [-w] [-f FIXES] [-m MAPPING] [MANDIR]")
+                }
+            };
+            let map = load_mapping(&mat)?;
+
+            let db = Database::load("database.txt")?;
+            let fixes = match mat.opt_str("f") {
+                Some(f) => suggest::load_fixes(f)?,
+                None => Default::default(),
+            };
+
+            let files = xrefs::pages(std::path::Path::new(mandir))?;
+            let mut changed = 0;
+            let mut applied = BTreeSet::new();
+            for f in files.iter() {
+                let input = match String::from_utf8(std::fs::read(f)?) {
+                    Ok(input) => input,
+                    Err(_) => {
+                        eprintln!(
+                            "WARNING {}: not UTF-8; skipped",
+                            f.display()
+                        );
+                        continue;
+                    }
+                };
+
+                let file = f
+                    .strip_prefix(mandir)
+                    .unwrap_or(f)
+                    .to_string_lossy()
+                    .to_string();
+                let mut unknown = Vec::new();
+                let rw = xrefs::rewrite(&input, &mut |page, sect| {
+                    let sect = sect.to_uppercase();
+                    let key =
+                        (file.to_string(), page.to_string(), sect.clone());
+                    if let Some((np, ns)) = fixes.get(&key) {
+                        /*
+                         * Fixes name pages as they are before the
+                         * renumbering.
+                         */
+                        applied.insert(key);
+                        let (ns, np) = map
+                            .page(ns, np)
+                            .unwrap_or_else(|| (ns.clone(), np.clone()));
+                        return Some((np, ns));
+                    }
+                    let (ns, np) = map.page(&sect, page)?;
+                    if db.lookup(&sect, page).is_none() {
+                        unknown.push(format!("{}({})", page, sect));
+                        return None;
+                    }
+                    Some((np, ns))
+                });
+                for u in unknown {
+                    eprintln!("WARNING {}: unknown page {}", f.display(), u);
+                }
+                if !rw.changed() {
+                    continue;
+                }
+                changed += 1;
+
+                if mat.opt_present("w") {
+                    std::fs::write(f, rw.text())
+                        .with_context(|| anyhow!("writing {:?}", f))?;
+                } else {
+                    let name = f.to_string_lossy();
+                    print!(
+                        "{}",
+                        diff::unified(
+                            name.trim_start_matches('/'),
+                            &rw.before,
+                            &rw.after
+                        )
+                    );
+                }
+            }
+            eprintln!("{} of {} pages changed", changed, files.len());
+
+            /*
+             * A fix that was never used names a reference that we did not
+             * find, so the page still needs attention.
+             */
+            let unused = fixes
+                .keys()
+                .filter(|k| !applied.contains(*k))
+                .collect::<Vec<_>>();
+            for (file, page, sect) in unused.iter() {
+                eprintln!(
+                    "ERROR {}: fix for {}({}) not applied",
+                    file, page, sect
+                );
+            }
+            if !unused.is_empty() {
+                bail!("{} of {} fixes not applied", unused.len(), fixes.len());
+            }
+        }
+        "links" => {
+            /*
+             * Check the links in the database, optionally as they will be
+             * once the renumbering is complete.
+             */
+            let mut opts = getopts::Options::new();
+            opts.optflag("n", "", "check the database after renumbering");
+            opts.optopt("m", "", "section mapping", "MAPPING");
+            let mat = opts.parse(std::env::args().skip(2))?;
+
+            let mut db = Database::load("database.txt")?;
+            if mat.opt_present("n") {
+                db = db.transform(&load_mapping(&mat)?);
+            }
+
+            let problems = links::check(&db);
+            let mut broken = 0;
+            for p in problems.iter() {
+                if p.broken(&db) {
+                    broken += 1;
+                }
+                match p {
+                    links::Problem::Unknown(r) => {
+                        println!("UNKNOWN TARGET {}({})", r.page, r.sect);
+                    }
+                    links::Problem::Dangling(r) => {
+                        let (ts, tp) = r.target.as_ref().unwrap();
+                        println!(
+                            "DANGLING {}({}) -> {}({}) [{}]",
+                            r.page, r.sect, tp, ts, r.pkg
+                        );
+                    }
+                    links::Problem::CrossSection(r) => {
+                        let (ts, tp) = r.target.as_ref().unwrap();
+                        println!(
+                            "CROSS-SECTION {}({}) -> {}({}) [{}]",
+                            r.page, r.sect, tp, ts, r.pkg
+                        );
+                    }
+                    links::Problem::Chain(r, chain) => {
+                        let mut out = format!("{}({})", r.page, r.sect);
+                        for (s, p) in chain.iter() {
+                            out += &format!(" -> {}({})", p, s);
+                        }
+                        println!("CHAIN {} [{}]", out, r.pkg);
+                    }
+                }
+            }
+
+            if broken > 0 {
+                bail!("{} broken links", broken);
+            }
+        }
         "mkdb" => {
-            let repo = "/ws/rti/packages/i386/nightly-nd/repo.redist";
+            let mut opts = getopts::Options::new();
+            opts.optopt(
+                "b",
+                "",
+                "use the newest version at or before this branch",
+                "BRANCH",
+            );
+            opts.optopt(
+                "t",
+                "",
+                "use the newest version at or before this timestamp",
+                "TIMESTAMP",
+            );
+            opts.optmulti(
+                "V",
+                "",
+                "include only actions for this variant value",
+                "NAME=VALUE",
+            );
+            opts.optopt(
+                "p",
+                "",
+                "take packages offered by several publishers from this one",
+                "PUBLISHER",
+            );
+            let mat = opts.parse(std::env::args().skip(2))?;
+            let vf = VariantFilter::parse(&mat.opt_strs("V"))?;
+
+            let pin = match (mat.opt_str("b"), mat.opt_str("t")) {
+                (Some(_), Some(_)) => bail!("-b and -t are mutually exclusive"),
+                (Some(b), None) => VersionPin::Branch(DotSequence::parse(&b)?),
+                (None, Some(t)) => VersionPin::Timestamp(parse_timestamp(&t)?),
+                (None, None) => VersionPin::Newest,
+            };
 
-            let list = pkgrepo_list(&repo, None)?;
+            let repo = mat.free.first().cloned().unwrap_or_else(|| {
+                "/ws/rti/packages/i386/nightly-nd/repo.redist".to_string()
+            });
+
+            /*
+             * The repository may contain several builds of each package, but
+             * we can only put one version of each in the database.
+             */
+            let files = open_repo(&repo)?.map(Arc::new);
+            let all = repo_list(&repo, files.as_deref(), None)?;
+            let prefer = mat.opt_str("p").or_else(|| {
+                files
+                    .as_ref()
+                    .and_then(|f| f.default_publisher())
+                    .map(str::to_string)
+            });
+            let list = select_versions(&all, &pin, prefer.as_deref())
+                .context("use -p to choose a publisher")?;
+            eprintln!(
+                "selected {} of {} package versions ({})",
+                list.len(),
+                all.len(),
+                pin
+            );
 
             let w = contents::PkgContents::new();
 
             for pkg in list {
                 w.append(vec![contents::PkgContentsWorkItem {
                     repo: repo.to_string(),
+                    files: files.clone(),
                     pkg,
                 }]);
             }
 
-            let mut db = Database::default();
+            let mut db = Database {
+                repo: Some(repo.to_string()),
+                selection: Some(pin.to_string()),
+                ..Default::default()
+            };
 
             let rx = w.run(8);
             while let Ok(r) = rx.recv() {
@@ -517,12 +1674,26 @@ fn main() -> Result<()> {
                 let p = &r[0];
                 eprintln!("{}", p.pkg.name());
 
+                let record = |link, sect, page, fileid: Option<&str>, target| {
+                    Record {
+                        link,
+                        sect,
+                        page,
+                        pkg: p.pkg.name().to_string(),
+                        publisher: p.pkg.publisher().map(str::to_string),
+                        version: p.pkg.version().map(|v| v.to_string()),
+                        fileid: fileid.map(str::to_string),
+                        orig: None,
+                        target,
+                    }
+                };
+
                 /*
                  * Get the contents and look for manual page files and links.
                  * Build a database that we can emit to a sorted file at the
                  * end.
                  */
-                for a in &p.contents {
+                for a in vf.filter(&p.contents) {
                     match &a {
                         Action::File(af) => {
                             if af.path().starts_with("usr/man") {
@@ -533,7 +1704,13 @@ fn main() -> Result<()> {
                             }
 
                             let (sect, page) = path_to_man(af.path())?;
-                            db.insert(false, &sect, &page, p.pkg.name())?;
+                            db.insert(record(
+                                false,
+                                sect,
+                                page,
+                                af.fileid(),
+                                None,
+                            ))?;
                         }
                         Action::Link(al) => {
                             if al.path() != "usr/man"
@@ -547,33 +1724,43 @@ fn main() -> Result<()> {
 
                             let (sect, page) = path_to_man(al.path())?;
 
-                            let mut t = al.target();
-                            if t.starts_with("../man1/") {
-                                t = t.trim_start_matches("../man1/");
-                            }
-                            if t.starts_with("../../../has/man/man1has/") {
-                                t = t.trim_start_matches(
-                                    "../../../has/man/man1has/",
-                                );
-                            }
-                            if t.starts_with("./") {
-                                t = t.trim_start_matches("./");
-                            }
-                            if t.contains('/') {
-                                bail!("target weird {:?}", al.target());
-                            }
-
-                            db.insert(true, &sect, &page, p.pkg.name())?;
+                            /*
+                             * The section of the target comes from the
+                             * directory it is in, wherever that is.  A few
+                             * links point at pages delivered under
+                             * usr/has/man, which we file in the same way.
+                             */
+                            let t = resolve_target(al.path(), al.target())
+                                .map(|t| {
+                                    t.replacen(
+                                        "usr/has/man/",
+                                        "usr/share/man/",
+                                        1,
+                                    )
+                                })
+                                .ok_or_else(|| {
+                                    anyhow!("target weird {:?}", al.target())
+                                })?;
+                            let (tsect, tpage) =
+                                path_to_man(&t).with_context(|| {
+                                    anyhow!("target weird {:?}", al.target())
+                                })?;
+
+                            let target = (tsect, tpage);
+                            db.insert(record(
+                                true,
+                                sect,
+                                page,
+                                None,
+                                Some(target),
+                            ))?;
                         }
                         _ => {}
                     }
                 }
             }
 
-            for rec in db.records {
-                let l = if rec.link { "l" } else { "f" };
-                println!("{}\t{}\t{}\t{}", l, rec.sect, rec.page, rec.pkg);
-            }
+            print!("{}", db.text());
         }
         x => {
             bail!("unknown command {:?}", x);
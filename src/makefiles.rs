@@ -0,0 +1,503 @@
+/*
+ * Update the Makefiles under usr/src/man in an illumos-gate workspace.  Each
+ * section directory (e.g., "man4") has a Makefile that lists its pages in
+ * MANFILES, MANLINKS and related variables, with a LINKSRC assignment for
+ * each link, and the Makefile above them lists every directory in SUBDIRS.
+ *
+ * When pages move to a new section, their entries must move to the Makefile
+ * for the new directory.  Each new Makefile is built from the existing one
+ * that contributes the most entries to it: only MANSECT, the lists and the
+ * LINKSRC assignments are replaced, and anything else is kept as it was.
+ */
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use super::mapping::Mapping;
+use super::p5m::new_target;
+
+const TABSTOP: usize = 8;
+const VALUE_COLUMN: usize = 16;
+
+enum Chunk {
+    Text(String),
+    ManSect(String),
+    List(String, Vec<String>),
+    LinkSrc(String),
+}
+
+struct Makefile {
+    sect: String,
+    lines: Vec<String>,
+    chunks: Vec<Chunk>,
+    lists: BTreeMap<String, Vec<String>>,
+    linksrc: BTreeMap<String, String>,
+}
+
+impl Makefile {
+    fn has_entries(&self) -> bool {
+        self.lists.values().any(|l| !l.is_empty())
+    }
+}
+
+/**
+ * A Makefile to be created, replaced or removed.  The contents before and
+ * after are None if the file does not exist at that point.
+ */
+pub struct Change {
+    pub path: PathBuf,
+    pub before: Option<Vec<String>>,
+    pub after: Option<Vec<String>>,
+}
+
+pub struct Plan {
+    pub changes: Vec<Change>,
+    pub warnings: Vec<String>,
+}
+
+fn parse(sect: &str, input: &str, warnings: &mut Vec<String>) -> Makefile {
+    lazy_static! {
+        static ref LIST: Regex = Regex::new(
+            r"^(?P<var>[A-Za-z0-9_]*MAN(?:FILES|LINKS|SOFILES)|SUBDIRS)\s*="
+        )
+        .unwrap();
+        static ref LINKSRC: Regex =
+            Regex::new(r"^(?P<link>\S+)\s+:=\s*LINKSRC\s*=\s*(?P<src>\S+)\s*$")
+                .unwrap();
+        static ref MANSECT: Regex =
+            Regex::new(r"^MANSECT\s*=\s*\S+\s*$").unwrap();
+    }
+
+    let lines = input.lines().map(str::to_string).collect::<Vec<_>>();
+    let mut chunks = Vec::new();
+    let mut lists = BTreeMap::new();
+    let mut linksrc = BTreeMap::new();
+
+    let mut i = 0;
+    while i < lines.len() {
+        let l = &lines[i];
+
+        if let Some(m) = LIST.captures(l) {
+            let var = m["var"].to_string();
+
+            /*
+             * The list continues for as long as lines end in a backslash.
+             */
+            let mut block = vec![l.to_string()];
+            while block.last().unwrap().ends_with('\\') && i + 1 < lines.len() {
+                i += 1;
+                block.push(lines[i].to_string());
+            }
+            i += 1;
+
+            let entries = block
+                .join(" ")
+                .split_once('=')
+                .unwrap()
+                .1
+                .split_whitespace()
+                .filter(|t| *t != "\\")
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+
+            if entries.iter().any(|e| e.contains('$')) {
+                /*
+                 * We cannot move entries that are made up of macros, so
+                 * leave the whole list alone.
+                 */
+                warnings.push(format!(
+                    "man{}/Makefile: {} uses macros; not updated",
+                    sect, var
+                ));
+                chunks.extend(block.into_iter().map(Chunk::Text));
+                continue;
+            }
+
+            lists.insert(var.to_string(), entries);
+            chunks.push(Chunk::List(var, block));
+            continue;
+        }
+
+        if let Some(m) = LINKSRC.captures(l) {
+            linksrc.insert(m["link"].to_string(), m["src"].to_string());
+            chunks.push(Chunk::LinkSrc(l.to_string()));
+        } else if MANSECT.is_match(l) {
+            chunks.push(Chunk::ManSect(l.to_string()));
+        } else {
+            chunks.push(Chunk::Text(l.to_string()));
+        }
+        i += 1;
+    }
+
+    Makefile {
+        sect: sect.to_string(),
+        lines,
+        chunks,
+        lists,
+        linksrc,
+    }
+}
+
+/**
+ * The first tab stop strictly after this column.
+ */
+fn next_stop(col: usize) -> usize {
+    (col / TABSTOP + 1) * TABSTOP
+}
+
+/**
+ * Enough tabs to move from one column to (at least) another, but always at
+ * least one tab.
+ */
+fn tabs(mut col: usize, to: usize) -> String {
+    let mut out = String::new();
+    loop {
+        out.push('\t');
+        col = next_stop(col);
+        if col >= to {
+            return out;
+        }
+    }
+}
+
+fn format_list(var: &str, entries: &[String]) -> Vec<String> {
+    let head = format!("{}=", var);
+    let vcol = next_stop(head.len()).max(VALUE_COLUMN);
+    let widest = entries.iter().map(|e| e.len()).max().unwrap_or(0);
+    let bcol = next_stop(vcol + widest);
+
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, e)| {
+            let mut l = if i == 0 {
+                format!("{}{}", head, tabs(head.len(), vcol))
+            } else {
+                tabs(0, vcol)
+            };
+            l += e;
+            if i + 1 < entries.len() {
+                l += &tabs(vcol + e.len(), bcol);
+                l.push('\\');
+            }
+            l
+        })
+        .collect()
+}
+
+fn format_linksrc(linksrc: &BTreeMap<String, String>) -> Vec<String> {
+    let widest = linksrc.keys().map(|l| l.len()).max().unwrap_or(0);
+    let col = next_stop(widest);
+
+    linksrc
+        .iter()
+        .map(|(link, src)| {
+            format!("{}{}:= LINKSRC = {}", link, tabs(link.len(), col), src)
+        })
+        .collect()
+}
+
+/**
+ * Produce a Makefile for section "sect" from a template, with new lists and
+ * link sources.  Lists and link sources that are unchanged from the template
+ * are reproduced exactly, so that a directory that gains or loses nothing
+ * does not change at all.
+ */
+fn render(
+    template: &Makefile,
+    sect: &str,
+    lists: &BTreeMap<String, Vec<String>>,
+    linksrc: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let same_linksrc = *linksrc == template.linksrc;
+
+    /*
+     * Lists and link sources with nowhere to go in the template are placed
+     * after the last list.
+     */
+    let last_list = template
+        .chunks
+        .iter()
+        .rposition(|c| matches!(c, Chunk::List(..)));
+    let mut extra = Vec::new();
+    for (var, entries) in lists.iter() {
+        if !entries.is_empty() && !template.lists.contains_key(var) {
+            extra.push(String::new());
+            extra.extend(format_list(var, entries));
+        }
+    }
+    if !linksrc.is_empty() && template.linksrc.is_empty() {
+        extra.push(String::new());
+        extra.extend(format_linksrc(linksrc));
+    }
+
+    let mut out = Vec::new();
+    let mut dropped = false;
+    let mut linksrc_done = false;
+    for (i, c) in template.chunks.iter().enumerate() {
+        match c {
+            Chunk::Text(l) => {
+                /*
+                 * Don't leave a pair of blank lines where a list was removed.
+                 */
+                if !(dropped && l.trim().is_empty()) {
+                    out.push(l.to_string());
+                }
+                dropped = false;
+            }
+            Chunk::ManSect(l) => {
+                if sect == template.sect {
+                    out.push(l.to_string());
+                } else {
+                    out.push(format!(
+                        "MANSECT={}{}",
+                        tabs(8, VALUE_COLUMN),
+                        sect
+                    ));
+                }
+                dropped = false;
+            }
+            Chunk::List(var, orig) => {
+                match lists.get(var) {
+                    Some(entries) if !entries.is_empty() => {
+                        if Some(entries) == template.lists.get(var) {
+                            out.extend(orig.iter().cloned());
+                        } else {
+                            out.extend(format_list(var, entries));
+                        }
+                        dropped = false;
+                    }
+                    _ => dropped = true,
+                }
+                if Some(i) == last_list {
+                    out.append(&mut extra);
+                }
+            }
+            Chunk::LinkSrc(l) => {
+                if same_linksrc {
+                    out.push(l.to_string());
+                } else if !linksrc_done {
+                    out.extend(format_linksrc(linksrc));
+                    linksrc_done = true;
+                }
+                dropped = linksrc.is_empty();
+            }
+        }
+    }
+
+    out
+}
+
+/**
+ * The directory (i.e., the section, in lower case) and the name of an entry
+ * from the Makefile for this section once it has moved.
+ */
+fn destination(
+    map: &Mapping,
+    entry: &str,
+    sect: &str,
+    warnings: &mut Vec<String>,
+) -> (String, String) {
+    let page = match entry.strip_suffix(&format!(".{}", sect)) {
+        Some(page) => page,
+        None => {
+            let to = match map.section(&sect.to_uppercase()) {
+                Some(to) => to.to_lowercase(),
+                None => sect.to_string(),
+            };
+            if to != sect {
+                warnings.push(format!(
+                    "man{}/Makefile: {} is not in section {}; \
+                    moved without renaming",
+                    sect, entry, sect
+                ));
+            }
+            return (to, entry.to_string());
+        }
+    };
+
+    match map.page(&sect.to_uppercase(), page) {
+        Some((to, page)) => {
+            let to = to.to_lowercase();
+            let entry = format!("{}.{}", page, to);
+            (to, entry)
+        }
+        None => (sect.to_string(), entry.to_string()),
+    }
+}
+
+/**
+ * Work out how the Makefiles in this man directory (i.e., usr/src/man) must
+ * change to reflect the renumbering.
+ */
+pub fn plan(map: &Mapping, mandir: &Path) -> Result<Plan> {
+    let mut warnings = Vec::new();
+
+    let mut old: BTreeMap<String, Makefile> = BTreeMap::new();
+    for ent in std::fs::read_dir(mandir)
+        .with_context(|| format!("reading {:?}", mandir))?
+    {
+        let ent = ent?;
+        let name = ent.file_name().to_string_lossy().to_string();
+        let sect = if let Some(sect) = name.strip_prefix("man") {
+            sect.to_string()
+        } else {
+            continue;
+        };
+
+        let path = ent.path().join("Makefile");
+        if !path.is_file() {
+            continue;
+        }
+        let input = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading {:?}", path))?;
+        old.insert(sect.to_string(), parse(&sect, &input, &mut warnings));
+    }
+
+    /*
+     * Move each entry to the directory for its new section, keeping track of
+     * which directories contributed entries to each new directory.
+     */
+    type Lists = BTreeMap<String, Vec<String>>;
+    let mut lists: BTreeMap<String, Lists> = BTreeMap::new();
+    let mut linksrc: BTreeMap<String, BTreeMap<String, String>> =
+        BTreeMap::new();
+    let mut contrib: BTreeMap<String, BTreeMap<String, usize>> =
+        BTreeMap::new();
+
+    for (sect, mf) in old.iter() {
+        for (var, entries) in mf.lists.iter() {
+            for e in entries.iter() {
+                let (to, e) = destination(map, e, sect, &mut warnings);
+                lists
+                    .entry(to.to_string())
+                    .or_default()
+                    .entry(var.to_string())
+                    .or_default()
+                    .push(e);
+                *contrib
+                    .entry(to.to_string())
+                    .or_default()
+                    .entry(sect.to_string())
+                    .or_default() += 1;
+            }
+        }
+
+        for (link, src) in mf.linksrc.iter() {
+            let path = format!("usr/share/man/man{}/{}", sect, link);
+            let src =
+                new_target(map, &path, src).unwrap_or_else(|| src.to_string());
+            let (to, link) = destination(map, link, sect, &mut warnings);
+            linksrc.entry(to).or_default().insert(link, src);
+        }
+    }
+
+    let mut changes = Vec::new();
+    let mut subdirs = Vec::new();
+    let mut sects = old.keys().cloned().collect::<Vec<_>>();
+    sects.extend(lists.keys().cloned());
+    sects.sort();
+    sects.dedup();
+
+    for sect in sects {
+        let path = mandir.join(format!("man{}", sect)).join("Makefile");
+        let existing = old.get(&sect);
+        let mut new_lists = lists.remove(&sect).unwrap_or_default();
+        let new_linksrc = linksrc.remove(&sect).unwrap_or_default();
+
+        if new_lists.values().all(|l| l.is_empty()) {
+            match existing {
+                Some(mf) if mf.has_entries() => {
+                    changes.push(Change {
+                        path,
+                        before: Some(mf.lines.clone()),
+                        after: None,
+                    });
+                }
+                Some(_) => subdirs.push(format!("man{}", sect)),
+                None => {}
+            }
+            continue;
+        }
+        subdirs.push(format!("man{}", sect));
+
+        /*
+         * Use the Makefile that contributes the most entries as the template,
+         * preferring the one already in this directory in the event of a tie.
+         * Entries from more than one directory need to be sorted together.
+         */
+        let from = &contrib[&sect];
+        let template = from
+            .iter()
+            .max_by_key(|(s, n)| (**n, **s == sect))
+            .map(|(s, _)| &old[s])
+            .unwrap();
+        if from.len() > 1 {
+            for l in new_lists.values_mut() {
+                l.sort();
+            }
+        }
+
+        let after = render(template, &sect, &new_lists, &new_linksrc);
+        match existing {
+            Some(mf) if mf.lines == after => {}
+            Some(mf) => changes.push(Change {
+                path,
+                before: Some(mf.lines.clone()),
+                after: Some(after),
+            }),
+            None => changes.push(Change {
+                path,
+                before: None,
+                after: Some(after),
+            }),
+        }
+    }
+
+    /*
+     * Finally, the parent Makefile must list the new set of directories.
+     */
+    let path = mandir.join("Makefile");
+    if path.is_file() {
+        let input = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading {:?}", path))?;
+        let mf = parse("", &input, &mut warnings);
+        if let Some(old_subdirs) = mf.lists.get("SUBDIRS") {
+            /*
+             * Keep any directories that are not section directories, or that
+             * we did not otherwise look at.
+             */
+            let mut want = old_subdirs
+                .iter()
+                .filter(|d| {
+                    d.strip_prefix("man").is_none_or(|s| !old.contains_key(s))
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            want.extend(subdirs);
+            want.sort();
+            want.dedup();
+
+            let mut sorted = old_subdirs.clone();
+            sorted.sort();
+            if want != sorted {
+                let mut new_lists = mf.lists.clone();
+                new_lists.insert("SUBDIRS".to_string(), want);
+                let after = render(&mf, "", &new_lists, &mf.linksrc);
+                changes.push(Change {
+                    path,
+                    before: Some(mf.lines),
+                    after: Some(after),
+                });
+            }
+        } else {
+            warnings.push(format!("{:?} has no SUBDIRS list", path));
+        }
+    }
+
+    Ok(Plan { changes, warnings })
+}
@@ -0,0 +1,192 @@
+/*
+ * A mapping from the sections (and names) that pages have today to the ones
+ * they will have once the renumbering is complete.  The mapping is read from
+ * a file of rules, one per line:
+ *
+ *      section OLD NEW                 move a section, or a subsection
+ *      page SECT PAGE NEWSECT [NEWPAGE]
+ *                                      move, or rename, a single page
+ *      title SECT TITLE...             the manual title for pages in a
+ *                                      section, e.g., "File Formats"
+ *
+ * A rule for a section that is only a number (e.g., "7") applies to its
+ * subsections as well, which keep their suffix: 7D becomes 4D.  A rule for a
+ * particular subsection (e.g., "7D" or "3XNET") takes precedence over the
+ * rule for its section, and a rule for a page takes precedence over both.
+ * A rule that maps something to itself keeps it where it is.  A page that
+ * moves takes the title of the section that it moves to.  Blank lines and
+ * lines that begin with "#" are ignored.
+ */
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+/**
+ * The renumbering proposed for illumos, which we use unless told otherwise.
+ */
+const DEFAULT: &str = "\
+section 1M 8
+section 4 5
+section 5 7
+section 7 4
+title 1M System Administration Commands
+title 4 File Formats
+title 5 Standards, Environments, and Macros
+title 7 Device and Network Interfaces
+title 8 System Administration Commands
+";
+
+pub struct Mapping {
+    sections: BTreeMap<String, String>,
+    pages: BTreeMap<(String, String), (String, String)>,
+    titles: BTreeMap<String, String>,
+}
+
+impl Default for Mapping {
+    fn default() -> Mapping {
+        Mapping::parse(DEFAULT).unwrap()
+    }
+}
+
+/**
+ * The section without any subsection; e.g., "3" for "3XNET".
+ */
+pub fn main_section(sect: &str) -> &str {
+    let end = sect
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(sect.len());
+    &sect[..end]
+}
+
+fn whole(sect: &str) -> bool {
+    !sect.is_empty() && main_section(sect) == sect
+}
+
+impl Mapping {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Mapping> {
+        let path = path.as_ref();
+        let input = std::fs::read_to_string(path)
+            .with_context(|| format!("reading {:?}", path))?;
+        Mapping::parse(&input).with_context(|| format!("parsing {:?}", path))
+    }
+
+    pub fn parse(input: &str) -> Result<Mapping> {
+        let mut m = Mapping {
+            sections: Default::default(),
+            pages: Default::default(),
+            titles: Default::default(),
+        };
+
+        for (n, l) in input.lines().enumerate() {
+            let t = l.split_whitespace().collect::<Vec<_>>();
+            if t.is_empty() || t[0].starts_with('#') {
+                continue;
+            }
+
+            match (t[0], t.len()) {
+                ("section", 3) => {
+                    let (old, new) = (t[1].to_uppercase(), t[2].to_uppercase());
+                    if whole(&old) && !whole(&new) {
+                        /*
+                         * A whole section must move to a whole section, so
+                         * that its subsections have somewhere to go.
+                         */
+                        bail!("line {}: cannot map {} to {}", n + 1, old, new);
+                    }
+                    if m.sections.insert(old.clone(), new).is_some() {
+                        bail!("line {}: second rule for {}", n + 1, old);
+                    }
+                }
+                ("page", 4 | 5) => {
+                    let old = (t[1].to_uppercase(), t[2].to_string());
+                    let new = (
+                        t[3].to_uppercase(),
+                        t.get(4).unwrap_or(&t[2]).to_string(),
+                    );
+                    if m.pages.insert(old.clone(), new).is_some() {
+                        bail!(
+                            "line {}: second rule for {}({})",
+                            n + 1,
+                            old.1,
+                            old.0
+                        );
+                    }
+                }
+                ("title", 3..) => {
+                    let sect = t[1].to_uppercase();
+                    let title = t[2..].join(" ");
+                    if m.titles.insert(sect.clone(), title).is_some() {
+                        bail!("line {}: second title for {}", n + 1, sect);
+                    }
+                }
+                _ => bail!("line {}: invalid rule {:?}", n + 1, l),
+            }
+        }
+
+        Ok(m)
+    }
+
+    /**
+     * Determine the section in which pages from this section will appear, or
+     * None if the section is not moving.  Rules for individual pages are not
+     * considered.
+     */
+    pub fn section(&self, sect: &str) -> Option<String> {
+        let new = if let Some(new) = self.sections.get(sect) {
+            new.to_string()
+        } else {
+            let main = main_section(sect);
+            let new = self.sections.get(main)?;
+            format!("{}{}", new, &sect[main.len()..])
+        };
+
+        if new == sect {
+            None
+        } else {
+            Some(new)
+        }
+    }
+
+    /**
+     * Determine the section and name that this page will have, or None if it
+     * is not moving.
+     */
+    pub fn page(&self, sect: &str, page: &str) -> Option<(String, String)> {
+        if let Some((ns, np)) =
+            self.pages.get(&(sect.to_string(), page.to_string()))
+        {
+            if ns == sect && np == page {
+                return None;
+            }
+            return Some((ns.to_string(), np.to_string()));
+        }
+
+        Some((self.section(sect)?, page.to_string()))
+    }
+
+    /**
+     * The manual title for pages in this section, if the mapping gives one.
+     */
+    pub fn title(&self, sect: &str) -> Option<&str> {
+        self.titles.get(sect).map(String::as_str)
+    }
+
+    /**
+     * Could pages in this section be affected by the mapping, either because
+     * they move out of it or because others move into it?
+     */
+    pub fn touches(&self, sect: &str) -> bool {
+        let main = main_section(sect);
+        let matches = |s: &String| s == sect || (whole(s) && s == main);
+
+        self.sections
+            .iter()
+            .any(|(from, to)| matches(from) || matches(to))
+            || self
+                .pages
+                .iter()
+                .any(|((from, _), (to, _))| from == sect || to == sect)
+    }
+}
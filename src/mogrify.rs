@@ -0,0 +1,739 @@
+/*
+ * Evaluate the transform and include directives understood by pkgmogrify(1)
+ * against a parsed manifest.  A directive looks like:
+ *
+ *      <transform TYPE... ATTR=REGEX... -> OPERATION ARGS...>
+ *      <include FILE>
+ *
+ * An action matches a transform if its type is one of the listed types (or
+ * no types are listed), and each listed attribute has a value matched by the
+ * regular expression.  As with pkgmogrify, the expression is anchored at the
+ * start of the value but not at the end.  The operations are:
+ *
+ *      add ATTR VALUE          append a value to an attribute
+ *      default ATTR VALUE      set an attribute only if it is not yet set
+ *      delete ATTR REGEX       remove any values matching the expression
+ *      drop                    discard the action
+ *      edit ATTR REGEX [REPL]  substitute within each value of an attribute
+ *      emit [ACTION]           emit another action after this one
+ *      exit [CODE [MESSAGE]]   stop processing altogether
+ *      print [MESSAGE]         print a message
+ *      set ATTR VALUE          replace every value of an attribute
+ *
+ * Operation arguments may refer to "%<N>", the Nth group captured by the
+ * matching expressions, and "%{ATTR}", the value of an attribute of the
+ * action (or a "pkg.*" attribute set elsewhere in the manifest).  Macros of
+ * the form "$(NAME)" are expanded before anything else is parsed.
+ *
+ * Transforms are applied to each action in the order in which they were
+ * loaded, and later transforms see the results of earlier ones.  Actions
+ * produced by "emit" are not themselves transformed.
+ */
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
+
+use super::ips::*;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Add(String, String),
+    Default(String, String),
+    Delete(String, String),
+    Drop,
+    Edit(String, String, String),
+    Emit(String),
+    Exit(i32, Option<String>),
+    Print(String),
+    Set(String, String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Transform {
+    types: Vec<String>,
+    matches: Vec<(String, Regex)>,
+    op: Op,
+    source: String,
+}
+
+impl Transform {
+    /**
+     * Parse the body of a transform directive; i.e., everything between
+     * "<transform" and the closing ">".
+     */
+    pub fn parse(body: &str, source: &str) -> Result<Transform> {
+        let (matching, operation) = if let Some(mo) = body.split_once("->") {
+            mo
+        } else {
+            bail!("{}: transform has no \"->\"", source);
+        };
+
+        let mut types = Vec::new();
+        let mut matches = Vec::new();
+        for w in split_words(matching)? {
+            if let Some((attr, re)) = w.split_once('=') {
+                let re =
+                    Regex::new(&format!("^(?:{})", re)).with_context(|| {
+                        format!("{}: invalid expression for {}", source, attr)
+                    })?;
+                matches.push((attr.to_string(), re));
+            } else {
+                types.push(w);
+            }
+        }
+
+        let operation = operation.trim();
+        let (opname, rest) = operation
+            .split_once(char::is_whitespace)
+            .map(|(o, r)| (o, r.trim()))
+            .unwrap_or((operation, ""));
+
+        /*
+         * The text emitted or printed is used verbatim, but other operations
+         * take a list of (possibly quoted) arguments.
+         */
+        let args = match opname {
+            "emit" | "print" => Vec::new(),
+            _ => split_words(rest)?,
+        };
+        let attr_value = |args: &[String]| -> Result<(String, String)> {
+            if args.len() < 2 {
+                bail!("{}: {} needs an attribute and a value", source, opname);
+            }
+            Ok((args[0].to_string(), args[1..].join(" ")))
+        };
+
+        let op = match opname {
+            "add" => {
+                let (a, v) = attr_value(&args)?;
+                Op::Add(a, v)
+            }
+            "default" => {
+                let (a, v) = attr_value(&args)?;
+                Op::Default(a, v)
+            }
+            "delete" => {
+                let (a, v) = attr_value(&args)?;
+                Op::Delete(a, v)
+            }
+            "set" => {
+                let (a, v) = attr_value(&args)?;
+                Op::Set(a, v)
+            }
+            "drop" => {
+                if !args.is_empty() {
+                    bail!("{}: drop takes no arguments", source);
+                }
+                Op::Drop
+            }
+            "edit" => match args.as_slice() {
+                [a, p] => Op::Edit(a.into(), p.into(), String::new()),
+                [a, p, r] => Op::Edit(a.into(), p.into(), r.into()),
+                _ => bail!("{}: edit needs ATTR REGEX [REPLACEMENT]", source),
+            },
+            "emit" => Op::Emit(rest.to_string()),
+            "print" => Op::Print(rest.to_string()),
+            "exit" => {
+                let code = if let Some(c) = args.first() {
+                    c.parse().with_context(|| {
+                        format!("{}: invalid exit code {:?}", source, c)
+                    })?
+                } else {
+                    0
+                };
+                let msg = if args.len() > 1 {
+                    Some(args[1..].join(" "))
+                } else {
+                    None
+                };
+                Op::Exit(code, msg)
+            }
+            o => bail!("{}: unknown transform operation {:?}", source, o),
+        };
+
+        Ok(Transform {
+            types,
+            matches,
+            op,
+            source: source.to_string(),
+        })
+    }
+
+    /**
+     * If the action matches, return the groups captured by the matching
+     * expressions, in order.
+     */
+    fn matches(&self, raw: &Raw) -> Option<Vec<Option<String>>> {
+        if !self.types.is_empty() && !self.types.contains(&raw.name) {
+            return None;
+        }
+
+        let mut groups = Vec::new();
+        for (attr, re) in self.matches.iter() {
+            let c = raw
+                .values(attr)
+                .iter()
+                .find_map(|v| re.captures(v).map(|c| cap_strings(&c)))?;
+            groups.extend(c);
+        }
+        Some(groups)
+    }
+}
+
+fn cap_strings(c: &regex::Captures) -> Vec<Option<String>> {
+    c.iter()
+        .skip(1)
+        .map(|m| m.map(|m| m.as_str().to_string()))
+        .collect()
+}
+
+/**
+ * Split directive arguments on whitespace.  Single or double quotes may be
+ * used to include whitespace in an argument, and are removed.  A backslash
+ * escapes a quote character or another backslash, but is otherwise left
+ * alone so that regular expressions survive intact.
+ */
+fn split_words(input: &str) -> Result<Vec<String>> {
+    let mut out = Vec::new();
+    let mut w: Option<String> = None;
+    let mut quote: Option<char> = None;
+    let mut i = input.chars().peekable();
+
+    while let Some(c) = i.next() {
+        if c == '\\' {
+            let w = w.get_or_insert_with(String::new);
+            match i.peek() {
+                Some(&n) if n == '\\' || n == '"' || n == '\'' => {
+                    w.push(n);
+                    i.next();
+                }
+                _ => w.push(c),
+            }
+        } else if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            } else {
+                w.get_or_insert_with(String::new).push(c);
+            }
+        } else if c == '"' || c == '\'' {
+            quote = Some(c);
+            w.get_or_insert_with(String::new);
+        } else if c.is_whitespace() {
+            if let Some(w) = w.take() {
+                out.push(w);
+            }
+        } else {
+            w.get_or_insert_with(String::new).push(c);
+        }
+    }
+
+    if quote.is_some() {
+        bail!("unterminated quote in {:?}", input);
+    }
+    if let Some(w) = w {
+        out.push(w);
+    }
+    Ok(out)
+}
+
+/**
+ * An action in a form that is easy to edit.  Once the transforms are done
+ * with it, we construct a new Action, which checks the result.
+ */
+struct Raw {
+    name: String,
+    positional: Vec<String>,
+    key: Option<String>,
+    attrs: Vec<(String, String)>,
+}
+
+impl Raw {
+    fn from_action(a: &Action) -> Raw {
+        Raw {
+            name: a.name().to_string(),
+            positional: a.positional().iter().map(|p| p.to_string()).collect(),
+            key: a.key_attr().map(str::to_string),
+            attrs: a
+                .vals()
+                .sorted()
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    fn to_action(&self) -> Result<Action> {
+        Action::from_parts(&self.name, &self.positional, &self.attrs)
+    }
+
+    /**
+     * Values of an attribute, including the pseudo-attributes "action.name",
+     * "action.hash" and "action.key".
+     */
+    fn values(&self, attr: &str) -> Vec<&str> {
+        match attr {
+            "action.name" => vec![self.name.as_str()],
+            "action.hash" => {
+                self.positional.iter().map(|p| p.as_str()).collect()
+            }
+            "action.key" => match &self.key {
+                Some(k) => self.values(k),
+                None => Vec::new(),
+            },
+            _ => self
+                .attrs
+                .iter()
+                .filter(|(k, _)| k == attr)
+                .map(|(_, v)| v.as_str())
+                .collect(),
+        }
+    }
+}
+
+pub struct Applied {
+    pub input: Action,
+    pub output: Vec<Action>,
+}
+
+impl Applied {
+    pub fn changed(&self) -> bool {
+        self.output.len() != 1
+            || self.output[0].to_string() != self.input.to_string()
+    }
+}
+
+pub struct Outcome {
+    pub applied: Vec<Applied>,
+    pub printed: Vec<String>,
+    pub exit: Option<(i32, Option<String>)>,
+}
+
+impl Outcome {
+    pub fn actions(&self) -> Vec<Action> {
+        self.applied
+            .iter()
+            .flat_map(|a| a.output.iter().cloned())
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub struct Mogrify {
+    transforms: Vec<Transform>,
+    include_dirs: Vec<PathBuf>,
+    macros: BTreeMap<String, String>,
+}
+
+impl Mogrify {
+    pub fn new() -> Mogrify {
+        Mogrify::default()
+    }
+
+    pub fn include_dir<P: AsRef<Path>>(&mut self, dir: P) {
+        self.include_dirs.push(dir.as_ref().to_path_buf());
+    }
+
+    pub fn define(&mut self, name: &str, value: &str) {
+        self.macros.insert(name.to_string(), value.to_string());
+    }
+
+    /**
+     * Replace each "$(NAME)" for which we have a definition.  Undefined
+     * macros are left alone, as pkgmogrify does.
+     */
+    fn expand(&self, l: &str) -> String {
+        let mut out = l.to_string();
+        for (k, v) in self.macros.iter() {
+            out = out.replace(&format!("$({})", k), v);
+        }
+        out
+    }
+
+    fn resolve(&self, name: &str) -> Result<PathBuf> {
+        let p = PathBuf::from(name);
+        if p.is_absolute() {
+            return Ok(p);
+        }
+
+        for dir in self.include_dirs.iter() {
+            let p = dir.join(name);
+            if p.is_file() {
+                return Ok(p);
+            }
+        }
+
+        if p.is_file() {
+            return Ok(p);
+        }
+
+        bail!("include file {:?} not found", name);
+    }
+
+    /**
+     * Load a file that may contain any mixture of transforms, includes and
+     * actions.  The transforms are kept for later application, and the
+     * actions are returned.
+     */
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<Action>> {
+        self.load_depth(path.as_ref(), 0)
+    }
+
+    fn load_depth(&mut self, path: &Path, depth: usize) -> Result<Vec<Action>> {
+        if depth > 64 {
+            bail!("includes nested too deeply at {:?}", path);
+        }
+
+        let input = std::fs::read_to_string(path)
+            .with_context(|| format!("reading {:?}", path))?;
+        self.load_input(&input, &path.display().to_string(), depth)
+    }
+
+    /**
+     * Load transforms and actions from a string, rather than from a file.  The
+     * name is used only to report errors.
+     */
+    pub fn load_str(&mut self, input: &str, name: &str) -> Result<Vec<Action>> {
+        self.load_input(input, name, 0)
+    }
+
+    fn load_input(
+        &mut self,
+        input: &str,
+        name: &str,
+        depth: usize,
+    ) -> Result<Vec<Action>> {
+        let mut out = Vec::new();
+
+        for (n, l) in manifest_lines(input) {
+            let source = format!("{}:{}", name, n);
+
+            /*
+             * A macro may expand to a comment character, to remove a line
+             * that does not apply to a particular platform.
+             */
+            let l = self.expand(&l);
+            let l = l.trim();
+            if l.is_empty() || l.starts_with('#') {
+                continue;
+            }
+
+            if let Some(d) = l.strip_prefix('<') {
+                let d = if let Some(d) = d.strip_suffix('>') {
+                    d.trim()
+                } else {
+                    bail!("{}: unterminated directive", source);
+                };
+
+                if let Some(body) = d.strip_prefix("transform") {
+                    self.transforms.push(Transform::parse(body, &source)?);
+                } else if let Some(name) = d.strip_prefix("include") {
+                    let p = self
+                        .resolve(name.trim())
+                        .with_context(|| source.to_string())?;
+                    out.extend(self.load_depth(&p, depth + 1)?);
+                } else {
+                    bail!("{}: unknown directive {:?}", source, l);
+                }
+                continue;
+            }
+
+            out.push(parse_action(l).with_context(|| source.to_string())?);
+        }
+
+        Ok(out)
+    }
+
+    /**
+     * Apply every transform to every action.
+     */
+    pub fn apply(&self, actions: &[Action]) -> Result<Outcome> {
+        /*
+         * Operations may refer to package attributes, like "pkg.fmri", that
+         * are set by set actions elsewhere in the manifest.
+         */
+        let mut pkg_attrs: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for a in actions.iter() {
+            if let Action::Set(s) = a {
+                pkg_attrs
+                    .entry(s.name().to_string())
+                    .or_default()
+                    .extend(s.values().iter().cloned());
+            }
+        }
+
+        let mut oc = Outcome {
+            applied: Vec::new(),
+            printed: Vec::new(),
+            exit: None,
+        };
+
+        for a in actions.iter() {
+            let output = self.apply_one(a, &pkg_attrs, &mut oc)?;
+            oc.applied.push(Applied {
+                input: a.clone(),
+                output,
+            });
+            if oc.exit.is_some() {
+                break;
+            }
+        }
+
+        Ok(oc)
+    }
+
+    fn apply_one(
+        &self,
+        a: &Action,
+        pkg_attrs: &BTreeMap<String, Vec<String>>,
+        oc: &mut Outcome,
+    ) -> Result<Vec<Action>> {
+        let mut raw = Raw::from_action(a);
+        let mut modified = false;
+        let mut dropped = false;
+        let mut emitted = Vec::new();
+
+        for t in self.transforms.iter() {
+            let groups = if let Some(g) = t.matches(&raw) {
+                g
+            } else {
+                continue;
+            };
+
+            let sub = |s: &str, raw: &Raw| -> Result<String> {
+                substitute(s, raw, &groups, pkg_attrs)
+                    .with_context(|| t.source.to_string())
+            };
+
+            match &t.op {
+                Op::Add(attr, v) => {
+                    let v = sub(v, &raw)?;
+                    raw.attrs.push((attr.to_string(), v));
+                    modified = true;
+                }
+                Op::Default(attr, v) => {
+                    if raw.values(attr).is_empty() {
+                        let v = sub(v, &raw)?;
+                        raw.attrs.push((attr.to_string(), v));
+                        modified = true;
+                    }
+                }
+                Op::Set(attr, v) => {
+                    let v = sub(v, &raw)?;
+                    raw.attrs.retain(|(k, _)| k != attr);
+                    raw.attrs.push((attr.to_string(), v));
+                    modified = true;
+                }
+                Op::Delete(attr, re) => {
+                    let re = Regex::new(&format!("^(?:{})", sub(re, &raw)?))
+                        .with_context(|| t.source.to_string())?;
+                    let before = raw.attrs.len();
+                    raw.attrs.retain(|(k, v)| k != attr || !re.is_match(v));
+                    modified |= raw.attrs.len() != before;
+                }
+                Op::Drop => {
+                    dropped = true;
+                    break;
+                }
+                Op::Edit(attr, re, repl) => {
+                    let re = Regex::new(&sub(re, &raw)?)
+                        .with_context(|| t.source.to_string())?;
+                    let repl = python_replacement(&sub(repl, &raw)?);
+                    for (k, v) in raw.attrs.iter_mut() {
+                        if k == attr {
+                            let nv = re.replace_all(v, repl.as_str());
+                            if nv != v.as_str() {
+                                *v = nv.to_string();
+                                modified = true;
+                            }
+                        }
+                    }
+                }
+                Op::Emit(l) => {
+                    let l = sub(l, &raw)?;
+                    let l = l.trim();
+                    if !l.is_empty() && !l.starts_with('#') {
+                        emitted.push(
+                            parse_action(l)
+                                .with_context(|| t.source.to_string())?,
+                        );
+                    }
+                }
+                Op::Print(m) => {
+                    oc.printed.push(sub(m, &raw)?);
+                }
+                Op::Exit(code, msg) => {
+                    let msg =
+                        msg.as_deref().map(|m| sub(m, &raw)).transpose()?;
+                    oc.exit = Some((*code, msg));
+                    break;
+                }
+            }
+        }
+
+        let mut out = Vec::new();
+        if !dropped {
+            if modified {
+                out.push(raw.to_action().with_context(|| {
+                    anyhow!("transformed action from {}", a)
+                })?);
+            } else {
+                out.push(a.clone());
+            }
+        }
+        out.extend(emitted);
+        Ok(out)
+    }
+}
+
+/**
+ * Expand "%<N>" and "%{ATTR}" references in an operation argument.  An
+ * attribute reference may carry options, separated by semicolons:
+ *
+ *      %{ATTR;notfound=TEXT;sep=TEXT;prefix=TEXT;suffix=TEXT}
+ *
+ * Without "notfound", it is an error for the attribute to be missing.
+ */
+fn substitute(
+    input: &str,
+    raw: &Raw,
+    groups: &[Option<String>],
+    pkg_attrs: &BTreeMap<String, Vec<String>>,
+) -> Result<String> {
+    let mut out = String::new();
+    let mut rest = input;
+
+    while let Some(i) = rest.find('%') {
+        out += &rest[..i];
+        rest = &rest[i..];
+
+        let (open, close) = match rest.as_bytes().get(1) {
+            Some(b'<') => ('<', '>'),
+            Some(b'{') => ('{', '}'),
+            _ => {
+                out.push('%');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let end = if let Some(end) = rest.find(close) {
+            end
+        } else {
+            bail!("unterminated %{} in {:?}", open, input);
+        };
+        let inner = &rest[2..end];
+        rest = &rest[end + 1..];
+
+        if open == '<' {
+            let n: usize = inner.parse().with_context(|| {
+                format!("invalid %<{}> in {:?}", inner, input)
+            })?;
+            match groups.get(n.wrapping_sub(1)) {
+                Some(Some(g)) => out += g,
+                Some(None) => {
+                    /*
+                     * pkgmogrify fails here too, rather than substitute
+                     * nothing for a group that did not match.
+                     */
+                    bail!(
+                        "group {} for %<{}> did not match in {:?}",
+                        n,
+                        n,
+                        input
+                    )
+                }
+                None => bail!("no group {} for %<{}> in {:?}", n, n, input),
+            }
+            continue;
+        }
+
+        let mut opts = inner.split(';');
+        let attr = opts.next().unwrap();
+        let mut notfound = None;
+        let mut sep = " ";
+        let mut prefix = "";
+        let mut suffix = "";
+        for o in opts {
+            match o.split_once('=') {
+                Some(("notfound", v)) => notfound = Some(v),
+                Some(("sep", v)) => sep = v,
+                Some(("prefix", v)) => prefix = v,
+                Some(("suffix", v)) => suffix = v,
+                _ => bail!("invalid option {:?} in {:?}", o, input),
+            }
+        }
+
+        let mut vals: Vec<&str> = raw.values(attr);
+        if vals.is_empty() && attr.starts_with("pkg.") {
+            if let Some(pv) = pkg_attrs.get(attr) {
+                vals = pv.iter().map(String::as_str).collect();
+            }
+        }
+
+        if vals.is_empty() {
+            if let Some(nf) = notfound {
+                out += nf;
+            } else {
+                bail!("attribute {:?} not found for {:?}", attr, input);
+            }
+            continue;
+        }
+
+        out += &vals
+            .iter()
+            .map(|v| format!("{}{}{}", prefix, v, suffix))
+            .collect::<Vec<_>>()
+            .join(sep);
+    }
+
+    out += rest;
+    Ok(out)
+}
+
+/**
+ * Transform files are written for Python, where a replacement refers to
+ * groups as "\1" or "\g<name>".  Convert to the "${1}" form used by the regex
+ * crate, escaping any literal "$".
+ */
+fn python_replacement(repl: &str) -> String {
+    let mut out = String::new();
+    let mut i = repl.chars().peekable();
+
+    while let Some(c) = i.next() {
+        match c {
+            '$' => out += "$$",
+            '\\' => match i.peek() {
+                Some(d) if d.is_ascii_digit() => {
+                    let mut n = String::new();
+                    while let Some(d) = i.peek().filter(|d| d.is_ascii_digit())
+                    {
+                        n.push(*d);
+                        i.next();
+                    }
+                    out += &format!("${{{}}}", n);
+                }
+                Some('g') => {
+                    i.next();
+                    if i.peek() == Some(&'<') {
+                        i.next();
+                        let name: String =
+                            i.by_ref().take_while(|&c| c != '>').collect();
+                        out += &format!("${{{}}}", name);
+                    } else {
+                        out += "\\g";
+                    }
+                }
+                Some('\\') => {
+                    i.next();
+                    out.push('\\');
+                }
+                _ => out.push('\\'),
+            },
+            c => out.push(c),
+        }
+    }
+
+    out
+}
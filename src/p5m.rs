@@ -0,0 +1,308 @@
+/*
+ * Rewrite the packaging manifests in an illumos-gate workspace (i.e., the
+ * .p5m files in usr/src/pkg/manifests) so that they deliver manual pages, and
+ * links to them, into their new sections.
+ *
+ * These files are maintained by hand, and are subject to review, so rather
+ * than render each action again in canonical form we parse it only to decide
+ * what must change, and then replace just the affected values in the original
+ * text.  Comments, blank lines, continuations, macros like "$(i386_ONLY)",
+ * attribute order and quoting are all left exactly as they were.  The one
+ * exception is a directory action that, once moved, would duplicate another,
+ * which is removed altogether.
+ */
+
+use std::collections::BTreeSet;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use super::ips::*;
+use super::mapping::Mapping;
+use super::path_to_man;
+use super::rewrite::Rewrite;
+
+/**
+ * Where a manual page, or a section directory, will be once the renumbering
+ * is complete; None if it is not moving.
+ */
+fn new_path(map: &Mapping, path: &str) -> Option<String> {
+    if let Some(dir) = path.strip_prefix("usr/share/man/man") {
+        if !dir.contains('/') {
+            let new = map.section(&dir.to_uppercase())?.to_lowercase();
+            return Some(format!("usr/share/man/man{}", new));
+        }
+    }
+
+    let (sect, page) = path_to_man(path).ok()?;
+    let (new, page) = map.page(&sect, &page)?;
+    let new = new.to_lowercase();
+    Some(format!("usr/share/man/man{}/{}.{}", new, page, new))
+}
+
+/**
+ * Link targets are relative to the directory that contains the link.  A
+ * target in the same directory usually moves along with the link, and one in
+ * a sibling section directory moves according to its own section; but a rule
+ * for a single page can separate a link from its target, which must then be
+ * reached through the sibling directory.
+ */
+pub fn new_target(map: &Mapping, path: &str, target: &str) -> Option<String> {
+    lazy_static! {
+        static ref SIBLING: Regex = Regex::new(
+            r"^(?P<up>(?:\.\./)+)man(?P<sect>[^/]+)/(?P<page>[^/]+)$"
+        )
+        .unwrap();
+    }
+
+    let (lsect, lpage) = path_to_man(path).ok()?;
+    let lnew = map
+        .page(&lsect, &lpage)
+        .map(|(s, _)| s)
+        .unwrap_or(lsect.clone());
+
+    let (up, sect, page, dot) = if let Some(m) = SIBLING.captures(target) {
+        let sect = &m["sect"];
+        let page = m["page"].strip_suffix(&format!(".{}", sect))?;
+        (
+            Some(m["up"].to_string()),
+            sect.to_uppercase(),
+            page.to_string(),
+            "",
+        )
+    } else {
+        let (dot, file) = match target.strip_prefix("./") {
+            Some(file) => ("./", file),
+            None => ("", target),
+        };
+        if file.contains('/') {
+            return None;
+        }
+        let page = file.strip_suffix(&format!(".{}", lsect.to_lowercase()))?;
+        (None, lsect.clone(), page.to_string(), dot)
+    };
+
+    let (new, page) = map.page(&sect, &page).unwrap_or((sect, page));
+    let new_lc = new.to_lowercase();
+    let out = match up {
+        Some(up) => format!("{}man{}/{}.{}", up, new_lc, page, new_lc),
+        None if new == lnew => format!("{}{}.{}", dot, page, new_lc),
+        None => format!("../man{}/{}.{}", new_lc, page, new_lc),
+    };
+
+    if out == target {
+        None
+    } else {
+        Some(out)
+    }
+}
+
+/**
+ * Determine which attribute values of this action must change, as a list of
+ * (key, old value, new value).
+ */
+fn replacements(
+    map: &Mapping,
+    a: &Action,
+) -> Vec<(&'static str, String, String)> {
+    let mut out = Vec::new();
+
+    match a {
+        Action::File(af) => {
+            if let Some(p) = new_path(map, af.path()) {
+                out.push(("path", af.path().to_string(), p));
+            }
+        }
+        Action::Dir(ad) => {
+            if let Some(p) = new_path(map, ad.path()) {
+                out.push(("path", ad.path().to_string(), p));
+            }
+        }
+        Action::Link(al) => {
+            if let Some(p) = new_path(map, al.path()) {
+                out.push(("path", al.path().to_string(), p));
+            }
+            if let Some(t) = new_target(map, al.path(), al.target()) {
+                out.push(("target", al.target().to_string(), t));
+            }
+        }
+        _ => {}
+    }
+
+    out
+}
+
+/**
+ * Replace the value of the attribute "key" in this line of text, if it is
+ * present and currently "old".  The value may be quoted.
+ */
+fn replace_value(
+    line: &str,
+    key: &str,
+    old: &str,
+    new: &str,
+) -> Option<String> {
+    let pat = format!("{}=", key);
+
+    let mut from = 0;
+    while let Some(i) = line[from..].find(&pat) {
+        let i = from + i;
+        let v = i + pat.len();
+        from = v;
+
+        if i > 0 && !line[..i].ends_with(char::is_whitespace) {
+            /*
+             * This is the tail of some other attribute name or value.
+             */
+            continue;
+        }
+
+        let (q, start) = match line[v..].chars().next() {
+            Some(c @ ('"' | '\'')) => (Some(c), v + 1),
+            _ => (None, v),
+        };
+        let rest = match line[start..].strip_prefix(old) {
+            Some(rest) => rest,
+            None => continue,
+        };
+        let end = match q {
+            Some(q) => rest.starts_with(q),
+            None => {
+                rest.is_empty()
+                    || rest
+                        .starts_with(|c: char| c.is_whitespace() || c == '\\')
+            }
+        };
+        if end {
+            return Some(format!("{}{}{}", &line[..start], new, rest));
+        }
+    }
+
+    None
+}
+
+/**
+ * Rewrite the text of a manifest.  Lines that cannot be parsed are left alone,
+ * with a warning if they appear to have something to do with manual pages.
+ */
+pub fn rewrite(map: &Mapping, input: &str) -> Rewrite {
+    lazy_static! {
+        static ref MACROS: Regex =
+            Regex::new(r"^(?:\$\([A-Za-z0-9_]+\))*").unwrap();
+    }
+
+    let mut rw = Rewrite::new(input);
+    let (before, after, warnings) =
+        (&rw.before, &mut rw.after, &mut rw.warnings);
+
+    /*
+     * Gather each logical line, as a list of the physical lines that make it
+     * up, in the same way as manifest_lines().
+     */
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut acc: Vec<usize> = Vec::new();
+    for (n, l) in before.iter().enumerate() {
+        acc.push(n);
+        if !l.ends_with('\\') {
+            groups.push(std::mem::take(&mut acc));
+        }
+    }
+    if !acc.is_empty() {
+        groups.push(acc);
+    }
+
+    /*
+     * Parse every action first, so that we know which directories the
+     * manifest already delivers before we move any of them.
+     */
+    let mut actions: Vec<(Vec<usize>, Action)> = Vec::new();
+    for g in groups {
+        let mut text = String::new();
+        for &n in g.iter() {
+            let l = before[n].trim_start();
+            text += l.strip_suffix('\\').unwrap_or(l);
+        }
+
+        let text = MACROS.replace(text.trim_start(), "");
+        if text.trim_end().is_empty()
+            || text.starts_with('#')
+            || text.starts_with('<')
+        {
+            continue;
+        }
+
+        let a = match parse_action(&text) {
+            Ok(a) => a,
+            Err(e) => {
+                if text.contains("usr/share/man/") {
+                    warnings.push(format!(
+                        "line {}: could not parse: {}",
+                        g[0] + 1,
+                        e
+                    ));
+                }
+                continue;
+            }
+        };
+
+        actions.push((g, a));
+    }
+
+    /*
+     * Moving a section directory can leave the manifest delivering the same
+     * directory twice; e.g., both "man1m" and "man8" become "man8".  The
+     * directories that stay where they are win, then the first of those that
+     * move, and each duplicate line is dropped.
+     */
+    let mut dirs = actions
+        .iter()
+        .filter_map(|(_, a)| match a {
+            Action::Dir(ad) if new_path(map, ad.path()).is_none() => {
+                Some(ad.path().to_string())
+            }
+            _ => None,
+        })
+        .collect::<BTreeSet<_>>();
+    let mut dropped = BTreeSet::new();
+
+    for (g, a) in actions {
+        if let Action::Dir(ad) = &a {
+            if let Some(p) = new_path(map, ad.path()) {
+                if !dirs.insert(p.clone()) {
+                    warnings.push(format!(
+                        "line {}: dropping dir {}, which would duplicate {}",
+                        g[0] + 1,
+                        ad.path(),
+                        p
+                    ));
+                    dropped.extend(g);
+                    continue;
+                }
+            }
+        }
+
+        'repl: for (key, old, new) in replacements(map, &a) {
+            for &n in g.iter() {
+                if let Some(l) = replace_value(&after[n], key, &old, &new) {
+                    after[n] = l;
+                    continue 'repl;
+                }
+            }
+            warnings.push(format!(
+                "line {}: could not find {}={} to replace",
+                g[0] + 1,
+                key,
+                old
+            ));
+        }
+    }
+
+    rw.after = std::mem::take(&mut rw.after)
+        .into_iter()
+        .enumerate()
+        .filter(|(n, _)| !dropped.contains(n))
+        .map(|(_, l)| l)
+        .collect();
+
+    rw
+}
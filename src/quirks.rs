@@ -0,0 +1,76 @@
+/*
+ * Some pages in illumos-gate have an unusual preamble: the lines that come
+ * before the ".TH" header, which are normally a block of comments carrying
+ * the copyright notice.  Rather than teach the page scanner about each one,
+ * we read them from a file of quirks, one per line:
+ *
+ *      title NAME      the page has no preamble, and begins with ".TH NAME"
+ *      preamble LINE   LINE, exactly, may begin the preamble
+ *
+ * Blank lines and lines that begin with "#" are ignored.
+ */
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use super::roff::arguments;
+
+#[derive(Default)]
+pub struct Quirks {
+    titles: BTreeSet<String>,
+    preamble: BTreeSet<String>,
+}
+
+impl Quirks {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Quirks> {
+        let path = path.as_ref();
+        let input = std::fs::read_to_string(path)
+            .with_context(|| format!("reading {:?}", path))?;
+        Quirks::parse(&input).with_context(|| format!("parsing {:?}", path))
+    }
+
+    pub fn parse(input: &str) -> Result<Quirks> {
+        let mut q = Quirks::default();
+
+        for (n, l) in input.lines().enumerate() {
+            if l.trim().is_empty() || l.starts_with('#') {
+                continue;
+            }
+
+            let (kind, rest) = l
+                .split_once(|c: char| c.is_ascii_whitespace())
+                .unwrap_or((l, ""));
+            let rest = rest.trim_start();
+            if rest.is_empty() {
+                bail!("line {}: quirk {:?} needs a value", n + 1, kind);
+            }
+
+            match kind {
+                "title" => q.titles.insert(rest.trim_end().to_string()),
+                "preamble" => q.preamble.insert(rest.to_string()),
+                other => bail!("line {}: unknown quirk {:?}", n + 1, other),
+            };
+        }
+
+        Ok(q)
+    }
+
+    /**
+     * Is this the header of a page known to have no preamble?
+     */
+    pub fn bare_title(&self, line: &str) -> bool {
+        line.strip_prefix(".TH ")
+            .and_then(|rest| arguments(rest).into_iter().next())
+            .map(|(_, _, title)| self.titles.contains(&title))
+            .unwrap_or(false)
+    }
+
+    /**
+     * Is this line known to begin the preamble of some page?
+     */
+    pub fn preamble(&self, line: &str) -> bool {
+        self.preamble.contains(line)
+    }
+}
@@ -0,0 +1,316 @@
+/*
+ * Produce pkgmogrify(1) transforms that move manual pages, and links to them,
+ * into their new sections.
+ *
+ * Because sections move in a cycle (4 to 5, 5 to 7, and 7 back to 4), and
+ * pkgmogrify applies each transform to the output of the previous one, we
+ * cannot simply rewrite each section in turn: a page moved from 4 to 5 would
+ * then be moved again, from 5 to 7.  Instead, we first move everything into a
+ * temporary directory that no transform matches, and then strip that
+ * directory from every path and link target in a final pass.
+ */
+
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+
+use super::ips::*;
+use super::mapping::Mapping;
+use super::mogrify::Mogrify;
+use super::{path_to_man, resolve_target, Database, Record};
+
+const MARKER: &str = ".renumber";
+
+/**
+ * Does this page have a rule of its own, rather than simply following its
+ * section?
+ */
+fn own_rule(map: &Mapping, sect: &str, page: &str) -> bool {
+    map.page(sect, page) != map.section(sect).map(|s| (s, page.to_string()))
+}
+
+/**
+ * Generate the text of a transform file that performs the renumbering for
+ * every moving section, and every page with a rule of its own, that appears
+ * in the database.
+ */
+pub fn transforms(db: &Database, map: &Mapping) -> String {
+    let sects = db
+        .records
+        .keys()
+        .filter_map(|sect| Some((sect.to_string(), map.section(sect)?)))
+        .collect::<BTreeSet<_>>();
+    let pages = db
+        .records()
+        .filter(|r| own_rule(map, &r.sect, &r.page))
+        .collect::<Vec<_>>();
+
+    let mut out = String::new();
+    out += "#\n";
+    out += "# Move manual pages into their new sections:\n";
+    out += "#\n";
+    for (old, new) in sects.iter() {
+        out += &format!("#\t{:<8} -> {}\n", old, new);
+    }
+    for r in pages.iter() {
+        let (ns, np) = new_page(map, &r.sect, &r.page);
+        out += &format!("#\t{}({}) -> {}({})\n", r.page, r.sect, np, ns);
+    }
+    out += "#\n";
+    out += "# Generated by futzman; do not edit.\n";
+    out += "#\n";
+
+    out += "\n# Targets of links within a section that moves:\n";
+    for (old, new) in sects.iter() {
+        let (old, new) = (old.to_lowercase(), new.to_lowercase());
+        out += &format!(
+            "<transform link path=usr/share/man/man{}/ \
+            target=((?:\\./)?)([^/]+)\\.{}$ -> set target %<1>%<2>.{}>\n",
+            old, old, new
+        );
+    }
+
+    out += "\n# Targets of links that point into another section:\n";
+    for (old, new) in sects.iter() {
+        let (old, new) = (old.to_lowercase(), new.to_lowercase());
+        out += &format!(
+            "<transform link target=(.*\\.\\./)man{}/([^/]+)\\.{}$ -> \
+            set target %<1>{}/man{}/%<2>.{}>\n",
+            old, old, MARKER, new, new
+        );
+    }
+
+    if !pages.is_empty() {
+        out += "\n# Pages with rules of their own:\n";
+    }
+    for r in pages.iter() {
+        let s = r.sect.to_lowercase();
+        let (ns, np) = new_page(map, &r.sect, &r.page);
+        let ns = ns.to_lowercase();
+        out += &format!(
+            "<transform file link path=usr/share/man/man{}/{}\\.{}$ -> \
+            set path usr/share/man/{}/man{}/{}.{}>\n",
+            s,
+            regex::escape(&r.page),
+            s,
+            MARKER,
+            ns,
+            np,
+            ns
+        );
+    }
+
+    out += "\n# The pages and links themselves:\n";
+    for (old, new) in sects.iter() {
+        let (old, new) = (old.to_lowercase(), new.to_lowercase());
+        out += &format!(
+            "<transform file link path=usr/share/man/man{}/(.+)\\.{}$ -> \
+            set path usr/share/man/{}/man{}/%<1>.{}>\n",
+            old, old, MARKER, new, new
+        );
+    }
+
+    /*
+     * A rule for a single page can separate a link from its target, or
+     * rename the target, which the rules for whole sections cannot express.
+     * Set the target of each such link outright, once it has moved.
+     */
+    let m = MARKER.replace('.', "\\.");
+    let links = db
+        .records()
+        .filter(|r| r.link)
+        .filter_map(|r| {
+            let (ts, tp) = target(r);
+            if !own_rule(map, &r.sect, &r.page) && !own_rule(map, &ts, &tp) {
+                return None;
+            }
+            Some((r, new_page(map, &ts, &tp)))
+        })
+        .collect::<Vec<_>>();
+    if !links.is_empty() {
+        out += "\n# Targets of links affected by rules for single pages:\n";
+    }
+    for (r, (ts, tp)) in links {
+        let (ls, lp) = new_page(map, &r.sect, &r.page);
+        let (ls, ts) = (ls.to_lowercase(), ts.to_lowercase());
+        let moved =
+            own_rule(map, &r.sect, &r.page) || map.section(&r.sect).is_some();
+        let dir = if moved {
+            format!("{}/man{}", m, ls)
+        } else {
+            format!("man{}", ls)
+        };
+        let t = if ts == ls {
+            format!("{}.{}", tp, ts)
+        } else {
+            format!("../man{}/{}.{}", ts, tp, ts)
+        };
+        out += &format!(
+            "<transform link path=usr/share/man/{}/{}\\.{}$ -> \
+            set target {}>\n",
+            dir,
+            regex::escape(&lp),
+            ls,
+            t
+        );
+    }
+
+    out += "\n# Finally, remove the temporary directory:\n";
+    out += &format!(
+        "<transform file link path=usr/share/man/{}/ -> edit path {}/>\n",
+        m, m
+    );
+    out += &format!("<transform link target=.*{}/ -> edit target {}/>\n", m, m);
+
+    out
+}
+
+/**
+ * The section and name that a page will have.
+ */
+fn new_page(map: &Mapping, sect: &str, page: &str) -> (String, String) {
+    map.page(sect, page)
+        .unwrap_or_else(|| (sect.to_string(), page.to_string()))
+}
+
+/**
+ * The page that a link points to.  Databases from older versions do not
+ * record it, so we assume such a link points to a page of the same name in
+ * its own section.
+ */
+fn target(r: &Record) -> (String, String) {
+    r.target
+        .clone()
+        .unwrap_or_else(|| (r.sect.to_string(), r.page.to_string()))
+}
+
+/**
+ * A file or link, by section and name, with the page a link points to.
+ */
+type Delivered = (bool, String, String, Option<(String, String)>);
+
+/**
+ * Check that the transforms do what we expect, by applying them to a
+ * manifest synthesised from the database and comparing the result with the
+ * transformed database.  Each link points at its target in the same directory
+ * or through the sibling directory for the section of the target, and we
+ * check that it still reaches the right page once it has moved.  Targets in
+ * the same directory may be written with or without a leading "./", so we
+ * try both.  Returns a description of each discrepancy.
+ */
+pub fn verify(
+    db: &Database,
+    map: &Mapping,
+    rules: &str,
+) -> Result<Vec<String>> {
+    let mut m = Mogrify::new();
+    m.load_str(rules, "generated transforms")?;
+
+    /*
+     * Pages that collide with one another after the renumbering are still
+     * delivered; that is a problem for the database, not the transforms.
+     */
+    let newdb = db.transform(map);
+    let want = newdb
+        .records()
+        .chain(newdb.collisions.iter())
+        .map(|r| {
+            let t = if r.link { Some(target(r)) } else { None };
+            (r.link, r.sect.to_string(), r.page.to_string(), t)
+        })
+        .collect::<BTreeSet<Delivered>>();
+
+    let mut problems = Vec::new();
+    for dot in ["", "./"] {
+        for p in check(db, &m, &want, dot)? {
+            if !problems.contains(&p) {
+                problems.push(p);
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+fn check(
+    db: &Database,
+    m: &Mogrify,
+    want: &BTreeSet<Delivered>,
+    dot: &str,
+) -> Result<Vec<String>> {
+    let mut actions = Vec::new();
+    for r in db.records() {
+        let s = r.sect.to_lowercase();
+        let path = format!("usr/share/man/man{}/{}.{}", s, r.page, s);
+        actions.push(if r.link {
+            let (ts, tp) = target(r);
+            let ts = ts.to_lowercase();
+            let t = if ts == s {
+                format!("{}{}.{}", dot, tp, ts)
+            } else {
+                format!("../man{}/{}.{}", ts, tp, ts)
+            };
+            Action::from_parts(
+                "link",
+                &[],
+                &[("path".to_string(), path), ("target".to_string(), t)],
+            )?
+        } else {
+            Action::from_parts("file", &[], &[("path".to_string(), path)])?
+        });
+    }
+
+    let oc = m.apply(&actions)?;
+
+    let mut problems = Vec::new();
+    let mut got = BTreeSet::new();
+    for a in oc.actions() {
+        let (link, path, target) = match &a {
+            Action::File(f) => (false, f.path(), None),
+            Action::Link(l) => (true, l.path(), Some(l.target())),
+            _ => {
+                problems.push(format!("unexpected action {}", a));
+                continue;
+            }
+        };
+
+        let (sect, page) = match path_to_man(path) {
+            Ok(sp) => sp,
+            Err(e) => {
+                problems.push(format!("{}: {}", a, e));
+                continue;
+            }
+        };
+
+        let target = match target.map(|t| resolve_target(path, t)) {
+            Some(Some(t)) => match path_to_man(&t) {
+                Ok(sp) => Some(sp),
+                Err(e) => {
+                    problems.push(format!("{}: target {}: {}", a, t, e));
+                    continue;
+                }
+            },
+            Some(None) => {
+                problems.push(format!("{}: target is outside the tree", a));
+                continue;
+            }
+            None => None,
+        };
+
+        got.insert((link, sect, page, target));
+    }
+
+    let describe = |(link, sect, page, t): &Delivered| match t {
+        Some((ts, tp)) => format!("link {}({}) -> {}({})", page, sect, tp, ts),
+        None if *link => format!("link {}({})", page, sect),
+        None => format!("file {}({})", page, sect),
+    };
+    for d in want.difference(&got) {
+        problems.push(format!("missing {}", describe(d)));
+    }
+    for d in got.difference(want) {
+        problems.push(format!("unexpected {}", describe(d)));
+    }
+
+    Ok(problems)
+}
@@ -0,0 +1,324 @@
+/*
+ * Read a pkg(5) file repository directly from disk, without the aid of
+ * pkgrepo(1).  The layout we understand is that of a version 4 repository:
+ *
+ *      pkg5.repository
+ *      publisher/<publisher>/catalog/catalog.*
+ *      publisher/<publisher>/pkg/<stem>/<version>
+ *
+ * where both the stem and the version are URL-encoded, and each file under
+ * the stem directory is the manifest for that version of the package.  The
+ * catalog, if present, is used to list packages; see the catalog module.
+ */
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use super::catalog::Catalog;
+use super::ips::*;
+
+pub struct Repository {
+    root: PathBuf,
+    publishers: Vec<String>,
+    default_publisher: Option<String>,
+}
+
+impl Repository {
+    /**
+     * Does this path look like the root of a file repository?
+     */
+    pub fn is_repository<P: AsRef<Path>>(path: P) -> bool {
+        path.as_ref().join("pkg5.repository").is_file()
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Repository> {
+        let root = path.as_ref().to_path_buf();
+
+        let cfgpath = root.join("pkg5.repository");
+        let cfg = std::fs::read_to_string(&cfgpath)
+            .with_context(|| format!("reading {:?}", cfgpath))?;
+        let cfg = parse_config(&cfg)
+            .with_context(|| format!("parsing {:?}", cfgpath))?;
+
+        if let Some(v) = cfg.get(&("repository".into(), "version".into())) {
+            if v != "4" {
+                bail!("repository {:?} has unsupported version {}", root, v);
+            }
+        }
+
+        let default_publisher = cfg
+            .get(&("publisher".into(), "prefix".into()))
+            .filter(|p| !p.is_empty())
+            .cloned();
+
+        let pubdir = root.join("publisher");
+        let mut publishers = Vec::new();
+        for ent in std::fs::read_dir(&pubdir)
+            .with_context(|| format!("reading {:?}", pubdir))?
+        {
+            let ent = ent?;
+            if !ent.file_type()?.is_dir() {
+                continue;
+            }
+            if let Some(n) = ent.file_name().to_str() {
+                publishers.push(n.to_string());
+            }
+        }
+        publishers.sort();
+
+        Ok(Repository {
+            root,
+            publishers,
+            default_publisher,
+        })
+    }
+
+    pub fn default_publisher(&self) -> Option<&str> {
+        self.default_publisher.as_deref()
+    }
+
+    fn pkgdir(&self, publisher: &str) -> PathBuf {
+        self.root.join("publisher").join(publisher).join("pkg")
+    }
+
+    fn catalogdir(&self, publisher: &str) -> PathBuf {
+        self.root.join("publisher").join(publisher).join("catalog")
+    }
+
+    /**
+     * Load the catalog for each publisher in the repository.
+     */
+    pub fn catalogs(&self) -> Result<Vec<Catalog>> {
+        self.publishers
+            .iter()
+            .map(|p| self.catalogdir(p))
+            .filter(|d| Catalog::exists(d))
+            .map(Catalog::load)
+            .collect()
+    }
+
+    /**
+     * List every version of every package in the repository, in the same form
+     * that "pkgrepo list" would report them.  If a pattern is provided, only
+     * packages with a matching stem are included; see stem_matches() for the
+     * rules.  We use the catalog where one exists, and otherwise walk the
+     * manifest directories.
+     */
+    pub fn list(&self, pattern: Option<&str>) -> Result<Vec<Package>> {
+        let mut out = Vec::new();
+
+        for publisher in self.publishers.iter() {
+            let catdir = self.catalogdir(publisher);
+            if Catalog::exists(&catdir) {
+                out.extend(
+                    Catalog::load(&catdir)?
+                        .entries()
+                        .iter()
+                        .map(|ce| ce.pkg())
+                        .filter(|pkg| {
+                            pattern.is_none_or(|p| stem_matches(p, pkg.name()))
+                        })
+                        .cloned(),
+                );
+                continue;
+            }
+
+            let pkgdir = self.pkgdir(publisher);
+            if !pkgdir.is_dir() {
+                continue;
+            }
+
+            for ent in std::fs::read_dir(&pkgdir)
+                .with_context(|| format!("reading {:?}", pkgdir))?
+            {
+                let ent = ent?;
+                if !ent.file_type()?.is_dir() {
+                    continue;
+                }
+                let stem = unquote(&ent.file_name().to_string_lossy())?;
+                if let Some(pattern) = pattern {
+                    if !stem_matches(pattern, &stem) {
+                        continue;
+                    }
+                }
+
+                for ent in std::fs::read_dir(ent.path())? {
+                    let ent = ent?;
+                    if !ent.file_type()?.is_file() {
+                        continue;
+                    }
+                    let version = unquote(&ent.file_name().to_string_lossy())?;
+
+                    out.push(Package::parse_fmri(&format!(
+                        "pkg://{}/{}@{}",
+                        publisher, stem, version
+                    ))?);
+                }
+            }
+        }
+
+        out.sort();
+        Ok(out)
+    }
+
+    /**
+     * Locate the manifest file for a specific package version.
+     */
+    pub fn manifest_path(&self, package: &Package) -> Result<PathBuf> {
+        let publisher = if let Some(p) = package.publisher() {
+            p
+        } else if let Some(p) = self.default_publisher.as_deref() {
+            p
+        } else if self.publishers.len() == 1 {
+            &self.publishers[0]
+        } else {
+            bail!("package {} has no publisher, and no default", package);
+        };
+
+        let version = if let Some(v) = package.version() {
+            v.to_string()
+        } else {
+            bail!("package {} has no version", package);
+        };
+
+        Ok(self
+            .pkgdir(publisher)
+            .join(quote(package.name()))
+            .join(quote(&version)))
+    }
+
+    pub fn contents(&self, package: &Package) -> Result<Vec<Action>> {
+        let path = self.manifest_path(package)?;
+        let manifest = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading manifest {:?}", path))?;
+        parse_manifest(&manifest)
+            .with_context(|| format!("parsing manifest {:?}", path))
+    }
+}
+
+/**
+ * The repository configuration file is in a simple INI format.  We return a
+ * map from (section, key) to value.
+ */
+fn parse_config(input: &str) -> Result<BTreeMap<(String, String), String>> {
+    let mut out = BTreeMap::new();
+    let mut section: Option<String> = None;
+
+    for l in input.lines() {
+        let l = l.trim();
+        if l.is_empty() || l.starts_with('#') || l.starts_with(';') {
+            continue;
+        }
+
+        if let Some(s) = l.strip_prefix('[') {
+            if let Some(s) = s.strip_suffix(']') {
+                section = Some(s.trim().to_string());
+                continue;
+            }
+            bail!("invalid section line {:?}", l);
+        }
+
+        let section = if let Some(s) = &section {
+            s
+        } else {
+            bail!("property outside of a section: {:?}", l);
+        };
+
+        if let Some((k, v)) = l.split_once('=') {
+            out.insert(
+                (section.to_string(), k.trim().to_string()),
+                v.trim().to_string(),
+            );
+        } else {
+            bail!("invalid property line {:?}", l);
+        }
+    }
+
+    Ok(out)
+}
+
+/**
+ * Package stems and versions are stored on disk encoded as if by Python's
+ * urllib.parse.quote() with no safe characters.
+ */
+pub fn quote(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"_.-~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out += &format!("%{:02X}", b);
+        }
+    }
+    out
+}
+
+pub fn unquote(s: &str) -> Result<String> {
+    let b = s.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] == b'%' {
+            let hex = s.get(i + 1..i + 3);
+            match hex.map(|h| u8::from_str_radix(h, 16)) {
+                Some(Ok(v)) => out.push(v),
+                _ => bail!("invalid escape in {:?}", s),
+            }
+            i += 3;
+        } else {
+            out.push(b[i]);
+            i += 1;
+        }
+    }
+    Ok(String::from_utf8(out)?)
+}
+
+/**
+ * Match a package stem against a pattern in roughly the way pkg(5) does: a
+ * pattern that begins with "/" or "pkg:/" must match the whole stem, while
+ * any other pattern may match any trailing set of path components.  The
+ * wildcard "*" matches any sequence of characters.
+ */
+pub fn stem_matches(pattern: &str, stem: &str) -> bool {
+    let pattern = pattern.split('@').next().unwrap();
+    if let Some(p) = pattern
+        .strip_prefix("pkg:/")
+        .or_else(|| pattern.strip_prefix('/'))
+    {
+        return glob_match(p, stem);
+    }
+
+    if glob_match(pattern, stem) {
+        return true;
+    }
+    stem.match_indices('/')
+        .any(|(i, _)| glob_match(pattern, &stem[i + 1..]))
+}
+
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let p = pattern.as_bytes();
+    let s = s.as_bytes();
+
+    let (mut pi, mut si) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while si < s.len() {
+        if pi < p.len() && p[pi] == b'*' {
+            star = Some((pi, si));
+            pi += 1;
+        } else if pi < p.len() && (p[pi] == b'?' || p[pi] == s[si]) {
+            pi += 1;
+            si += 1;
+        } else if let Some((sp, ss)) = star {
+            pi = sp + 1;
+            si = ss + 1;
+            star = Some((sp, ss + 1));
+        } else {
+            return false;
+        }
+    }
+
+    p[pi..].iter().all(|&c| c == b'*')
+}
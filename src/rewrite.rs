@@ -0,0 +1,58 @@
+/*
+ * The commands that rewrite files in an illumos-gate workspace (i.e., "p5m",
+ * "sources" and "xrefs") change only the lines that must change, and leave
+ * every other byte as it was, so that the result can be reviewed as a diff
+ * against the original.
+ */
+
+pub struct Rewrite {
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+    pub warnings: Vec<String>,
+    trailing_newline: bool,
+}
+
+impl Rewrite {
+    /**
+     * Begin to rewrite this text, with every line as it was.  We split on
+     * newlines alone, so that any carriage returns are preserved.
+     */
+    pub fn new(input: &str) -> Rewrite {
+        let body = input.strip_suffix('\n').unwrap_or(input);
+        let before = body.split('\n').map(str::to_string).collect::<Vec<_>>();
+
+        Rewrite {
+            after: before.clone(),
+            before,
+            warnings: Vec::new(),
+            trailing_newline: input.ends_with('\n'),
+        }
+    }
+
+    pub fn changed(&self) -> bool {
+        self.before != self.after
+    }
+
+    /**
+     * The complete text of the rewritten file.
+     */
+    pub fn text(&self) -> String {
+        let mut out = self.after.join("\n");
+        if self.trailing_newline {
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/**
+ * Write a new section, or page name, in the same case as the one it replaces.
+ * Sections are usually written in upper case, but not always.
+ */
+pub fn same_case(old: &str, new: &str) -> String {
+    if old.chars().any(|c| c.is_ascii_lowercase()) {
+        new.to_lowercase()
+    } else {
+        new.to_uppercase()
+    }
+}
@@ -0,0 +1,531 @@
+/*
+ * Routines for picking apart roff(7) source.
+ */
+
+/**
+ * Split the arguments to a request, as roff would, returning the byte range
+ * of each argument (including any quotes) and its value.  Within a quoted
+ * argument, a pair of double quotes stands for one.
+ */
+pub fn arguments(line: &str) -> Vec<(usize, usize, String)> {
+    let b = line.as_bytes();
+    let mut out = Vec::new();
+
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] == b' ' || b[i] == b'\t' {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        let mut val = String::new();
+        if b[i] == b'"' {
+            i += 1;
+            while i < b.len() {
+                if b[i] == b'"' {
+                    if b.get(i + 1) == Some(&b'"') {
+                        val.push('"');
+                        i += 2;
+                        continue;
+                    }
+                    i += 1;
+                    break;
+                }
+                let c = line[i..].chars().next().unwrap();
+                val.push(c);
+                i += c.len_utf8();
+            }
+        } else {
+            while i < b.len() && b[i] != b' ' && b[i] != b'\t' {
+                let c = line[i..].chars().next().unwrap();
+                val.push(c);
+                i += c.len_utf8();
+            }
+        }
+        out.push((start, i, val));
+    }
+
+    out
+}
+
+/**
+ * Remove a comment, introduced with \" or \#, from the end of a line.
+ */
+pub fn strip_comment(line: &str) -> &str {
+    let b = line.as_bytes();
+
+    let mut i = 0;
+    while i + 1 < b.len() {
+        if b[i] == b'\\' {
+            if b[i + 1] == b'"' || b[i + 1] == b'#' {
+                return &line[..i];
+            }
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+
+    line
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Font {
+    #[default]
+    Roman,
+    Bold,
+    Italic,
+    BoldItalic,
+}
+
+impl Font {
+    fn from_name(name: &str) -> Font {
+        match name {
+            "B" | "3" | "CB" => Font::Bold,
+            "I" | "2" | "CI" => Font::Italic,
+            "BI" | "4" => Font::BoldItalic,
+            _ => Font::Roman,
+        }
+    }
+}
+
+/**
+ * A character of text as it would be formatted, with the font in which it
+ * would appear and the position of its source in the input (both counted from
+ * one).  The source, which may be an escape, ends before byte "end" of the
+ * line; characters that roff supplies itself have no source, and end where
+ * they begin.  Each input line that would break the text ends with a newline.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Char {
+    pub c: char,
+    pub font: Font,
+    pub line: usize,
+    pub col: usize,
+    pub end: usize,
+}
+
+/**
+ * Stands in for special characters, strings, and other escapes that produce
+ * some unknown text.
+ */
+const OTHER: char = '\u{fffd}';
+
+#[derive(PartialEq, Default)]
+enum Tbl {
+    #[default]
+    Off,
+    Format,
+    Data,
+}
+
+/**
+ * Turns man(7) source into text, a line at a time.  This is not a complete
+ * implementation of roff, but it knows enough about requests, font macros,
+ * escapes and tbl(1) tables to find the text in a page, and the font in
+ * which each part of it will appear.
+ */
+#[derive(Default)]
+pub struct Text {
+    chars: Vec<Char>,
+    font: Font,
+    prev: Font,
+    next_line: Option<Font>,
+    joined: bool,
+    tbl: Tbl,
+}
+
+impl Text {
+    pub fn chars(&self) -> &[Char] {
+        &self.chars
+    }
+
+    fn push(&mut self, c: char, line: usize, col: usize) {
+        self.chars.push(Char {
+            c,
+            font: self.font,
+            line,
+            col,
+            end: col - 1,
+        });
+    }
+
+    fn set_font(&mut self, name: &str) {
+        let f = if name == "P" || name.is_empty() {
+            self.prev
+        } else {
+            Font::from_name(name)
+        };
+        self.prev = self.font;
+        self.font = f;
+    }
+
+    /**
+     * Decode text in the current font, from a string that begins at byte
+     * offset "base" within input line "line".
+     */
+    fn decode(&mut self, s: &str, line: usize, base: usize) {
+        let cs = s.char_indices().collect::<Vec<_>>();
+
+        /*
+         * Escapes take a name that is either a single character, two
+         * characters after "(", or anything up to the "]" after "[".
+         */
+        let name = |i: &mut usize| -> String {
+            match cs.get(*i).map(|c| c.1) {
+                Some('(') => {
+                    let n = cs.iter().skip(*i + 1).take(2).map(|c| c.1);
+                    *i += 3;
+                    n.collect()
+                }
+                Some('[') => {
+                    let n = cs
+                        .iter()
+                        .skip(*i + 1)
+                        .take_while(|c| c.1 != ']')
+                        .map(|c| c.1)
+                        .collect::<String>();
+                    *i += n.chars().count() + 2;
+                    n
+                }
+                Some(c) => {
+                    *i += 1;
+                    c.to_string()
+                }
+                None => String::new(),
+            }
+        };
+
+        /*
+         * Once we have moved past an escape, we know where the source of the
+         * characters it produced ends.
+         */
+        let mut first = self.chars.len();
+        let mut ended = |chars: &mut [Char], i: usize| {
+            let end = base + cs.get(i).map(|c| c.0).unwrap_or(s.len());
+            for c in chars[first..].iter_mut() {
+                c.end = end;
+            }
+            first = chars.len();
+        };
+
+        self.joined = false;
+        let mut i = 0;
+        while i < cs.len() {
+            ended(&mut self.chars, i);
+            let (off, c) = cs[i];
+            let col = base + off + 1;
+            i += 1;
+
+            if c != '\\' {
+                self.push(c, line, col);
+                continue;
+            }
+
+            let e = if let Some(&(_, e)) = cs.get(i) {
+                e
+            } else {
+                /*
+                 * A backslash at the end of the line joins the next one.
+                 */
+                self.joined = true;
+                return;
+            };
+            i += 1;
+
+            match e {
+                '"' | '#' => return,
+                'f' => {
+                    let n = name(&mut i);
+                    self.set_font(&n);
+                }
+                '-' => self.push('-', line, col),
+                'e' | '\\' => self.push('\\', line, col),
+                'c' => self.joined = true,
+                '&' | '%' | '|' | '^' | ')' | ':' | '{' | '}' => {}
+                '0' | ' ' | '~' => self.push(' ', line, col),
+                '(' | '[' => {
+                    i -= 1;
+                    let c = match name(&mut i).as_str() {
+                        "hy" | "mi" => '-',
+                        "aq" => '\'',
+                        "dq" | "lq" | "rq" => '"',
+                        _ => OTHER,
+                    };
+                    self.push(c, line, col);
+                }
+                '*' | 'n' => {
+                    if e == 'n'
+                        && matches!(cs.get(i).map(|c| c.1), Some('+' | '-'))
+                    {
+                        i += 1;
+                    }
+                    name(&mut i);
+                    self.push(OTHER, line, col);
+                }
+                'g' | 'm' | 'M' | 'V' | 'Y' | '$' | 'k' | 'z' => {
+                    name(&mut i);
+                }
+                's' => {
+                    if matches!(cs.get(i).map(|c| c.1), Some('+' | '-')) {
+                        i += 1;
+                    }
+                    match cs.get(i).map(|c| c.1) {
+                        Some('(' | '[') => {
+                            name(&mut i);
+                        }
+                        Some('1'..='3')
+                            if cs
+                                .get(i + 1)
+                                .is_some_and(|c| c.1.is_ascii_digit()) =>
+                        {
+                            i += 2;
+                        }
+                        _ => i += 1,
+                    }
+                }
+                'h' | 'v' | 'w' | 'o' | 'l' | 'L' | 'D' | 'X' | 'b' | 'x'
+                | 'A' | 'B' | 'C' | 'N' | 'R' | 'S' | 'Z' => {
+                    /*
+                     * These take an argument between a pair of delimiters.
+                     */
+                    if let Some(&(_, d)) = cs.get(i) {
+                        i += 1;
+                        while i < cs.len() && cs[i].1 != d {
+                            i += 1;
+                        }
+                        i += 1;
+                    }
+                    if matches!(e, 'w' | 'C' | 'N') {
+                        self.push(OTHER, line, col);
+                    }
+                }
+                e => self.push(e, line, col),
+            }
+        }
+        ended(&mut self.chars, i);
+    }
+
+    /**
+     * Decode each argument to a request in turn, with a font for each.
+     */
+    fn arguments(
+        &mut self,
+        rest: &str,
+        base: usize,
+        line: usize,
+        fonts: &[Font],
+        spaced: bool,
+    ) {
+        let (font, prev) = (self.font, self.prev);
+
+        for (k, (s, e, _)) in arguments(rest).into_iter().enumerate() {
+            let (s, e) = if rest[s..].starts_with('"') {
+                (
+                    s + 1,
+                    if e > s + 1 && rest[..e].ends_with('"') {
+                        e - 1
+                    } else {
+                        e
+                    },
+                )
+            } else {
+                (s, e)
+            };
+
+            self.font = fonts[k % fonts.len()];
+            if spaced && k > 0 {
+                self.push(' ', line, base + s);
+            }
+            self.decode(&rest[s..e], line, base + s);
+        }
+
+        (self.font, self.prev) = (font, prev);
+    }
+
+    /**
+     * Process the next line of input, which is line "n" of the file.
+     */
+    pub fn line(&mut self, n: usize, l: &str) {
+        self.joined = false;
+
+        if self.tbl == Tbl::Format {
+            /*
+             * Skip the options and format lines of a table.  The last format
+             * line ends with a period.
+             */
+            if strip_comment(l).trim_end().ends_with('.') {
+                self.tbl = Tbl::Data;
+            }
+            return;
+        }
+
+        if l.starts_with('.') || l.starts_with('\'') {
+            let l = strip_comment(l);
+            let req = l[1..].trim_start_matches([' ', '\t']);
+            let (name, rest) = req.split_once([' ', '\t']).unwrap_or((req, ""));
+            let base = l.len() - rest.len();
+
+            use Font::*;
+            match name {
+                "TS" | "T&" => {
+                    self.tbl = Tbl::Format;
+                    return;
+                }
+                "TE" => self.tbl = Tbl::Off,
+                "B" | "I" | "SB" => {
+                    let f = if name == "I" { Italic } else { Bold };
+                    if rest.trim().is_empty() {
+                        /*
+                         * The font applies to the next line of text.
+                         */
+                        self.next_line = Some(f);
+                        return;
+                    }
+                    self.arguments(rest, base, n, &[f], true);
+                }
+                "SM" => self.arguments(rest, base, n, &[self.font], true),
+                "BR" => self.arguments(rest, base, n, &[Bold, Roman], false),
+                "RB" => self.arguments(rest, base, n, &[Roman, Bold], false),
+                "BI" => self.arguments(rest, base, n, &[Bold, Italic], false),
+                "IB" => self.arguments(rest, base, n, &[Italic, Bold], false),
+                "IR" => self.arguments(rest, base, n, &[Italic, Roman], false),
+                "RI" => self.arguments(rest, base, n, &[Roman, Italic], false),
+                "IP" => {
+                    /*
+                     * Only the tag is text; the other argument is an indent.
+                     */
+                    if let Some((_, e, _)) = arguments(rest).first() {
+                        let tag = &rest[..*e];
+                        self.arguments(tag, base, n, &[self.font], true);
+                    }
+                }
+                _ => {}
+            }
+
+            self.push('\n', n, l.len() + 1);
+            return;
+        }
+
+        let mut text = l;
+        let mut base = 0;
+        if self.tbl == Tbl::Data {
+            /*
+             * Text blocks in table cells are delimited by "T{" and "T}".
+             */
+            if let Some(t) = text.strip_prefix("T}") {
+                text = t;
+                base = 2;
+            }
+            text = text.strip_suffix("T{").unwrap_or(text);
+        }
+
+        if let Some(f) = self.next_line.take() {
+            let (font, prev) = (self.font, self.prev);
+            self.font = f;
+            self.decode(text, n, base);
+            (self.font, self.prev) = (font, prev);
+        } else {
+            self.decode(text, n, base);
+        }
+
+        if !self.joined {
+            self.push('\n', n, l.len() + 1);
+        }
+    }
+}
+
+/**
+ * A line of input (counted from one), and a range of bytes within it.
+ */
+pub type Span = (usize, usize, usize);
+
+/**
+ * Where the source of this text is, if it all comes from one line.
+ */
+fn span(text: &[Char]) -> Option<Span> {
+    let (first, last) = (text.first()?, text.last()?);
+    if text.iter().any(|c| c.line != first.line || c.end < c.col) {
+        return None;
+    }
+    Some((first.line, first.col - 1, last.end))
+}
+
+/**
+ * A reference to another page, and where it begins in the input.  Where we
+ * can, we also record the source of the page name and of the section.
+ */
+#[derive(Debug, Clone)]
+pub struct Reference {
+    pub page: String,
+    pub sect: String,
+    pub line: usize,
+    pub col: usize,
+    pub source: Option<(Span, Span)>,
+}
+
+fn is_name(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '.' | '-')
+}
+
+/**
+ * Find references in formatted text.  A reference is a page name in bold or
+ * italics, followed by a section in parentheses: e.g., "\fBpasswd\fR(4)" or
+ * ".BR passwd (4)".  The section may be on the line after the name, as with
+ * ".B passwd" followed by "(4)".
+ */
+pub fn references(text: &[Char]) -> Vec<Reference> {
+    let mut out = Vec::new();
+
+    for i in 0..text.len() {
+        if text[i].c != '(' {
+            continue;
+        }
+
+        let sect = text[i + 1..]
+            .iter()
+            .map(|c| c.c)
+            .take_while(char::is_ascii_alphanumeric)
+            .collect::<String>();
+        if !sect.starts_with(|c: char| c.is_ascii_digit())
+            || text.get(i + 1 + sect.len()).map(|c| c.c) != Some(')')
+        {
+            continue;
+        }
+
+        let mut end = i;
+        if end > 0 && text[end - 1].c == '\n' {
+            end -= 1;
+        }
+        let mut start = end;
+        while start > 0
+            && is_name(text[start - 1].c)
+            && text[start - 1].font != Font::Roman
+        {
+            start -= 1;
+        }
+        if start == end
+            || text[start].c == '-'
+            || (start > 0 && is_name(text[start - 1].c))
+        {
+            /*
+             * There is no name, or it is probably an option, or the part in
+             * bold or italics is only the tail of a longer word.
+             */
+            continue;
+        }
+
+        let source =
+            span(&text[start..end]).zip(span(&text[i + 1..i + 1 + sect.len()]));
+        out.push(Reference {
+            page: text[start..end].iter().map(|c| c.c).collect(),
+            sect,
+            line: text[start].line,
+            col: text[start].col,
+            source,
+        });
+    }
+
+    out
+}
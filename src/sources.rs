@@ -0,0 +1,254 @@
+/*
+ * Move the source for each manual page in an illumos-gate workspace (e.g.,
+ * usr/src/man/man4/passwd.4) to the directory for its new section, and
+ * rewrite the section in its header: the second argument to ".TH" for pages
+ * written with man(7) macros, or to ".Dt" for mdoc(7) pages.  The manual
+ * title, the fifth argument to ".TH", names the section, so it must change
+ * as well: a page that has the title of its old section takes the title of
+ * its new one, per the "title" rules in the mapping.  Subsections have titles
+ * of their own (e.g., "Devices" for 7D), which change only if the mapping
+ * gives titles for them.  mdoc pages do not carry a title; mandoc works it
+ * out from the section.
+ */
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::mapping::{main_section, Mapping};
+use super::rewrite::{same_case, Rewrite};
+use super::roff::arguments;
+
+pub struct Move {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub page: Rewrite,
+}
+
+pub struct Plan {
+    pub moves: Vec<Move>,
+    pub warnings: Vec<String>,
+}
+
+/**
+ * Rewrite the header of a page that is moving from one section to another,
+ * and perhaps changing its name from names.0 to names.1.  Returns the new
+ * lines, or an error if there is no header to rewrite.
+ */
+fn rewrite_header(
+    map: &Mapping,
+    lines: &[String],
+    old: &str,
+    new: &str,
+    names: (&str, &str),
+    warnings: &mut Vec<String>,
+) -> Result<Vec<String>> {
+    let mut out = lines.to_vec();
+
+    let n = lines
+        .iter()
+        .position(|l| l.starts_with(".TH ") || l.starts_with(".Dt "))
+        .ok_or_else(|| anyhow!("no .TH or .Dt header"))?;
+    let l = &lines[n];
+    let args = arguments(&l[4..]);
+
+    /*
+     * Replace arguments from the end of the line, so that the byte ranges of
+     * earlier arguments remain valid.
+     */
+    let mut repl: Vec<(usize, usize, String)> = Vec::new();
+
+    if names.0 != names.1 {
+        match args.first() {
+            Some((s, e, v)) if v.eq_ignore_ascii_case(names.0) => {
+                let new = if v.chars().any(|c| c.is_ascii_lowercase()) {
+                    names.1.to_string()
+                } else {
+                    names.1.to_uppercase()
+                };
+                repl.push((*s, *e, new));
+            }
+            Some((_, _, v)) => warnings.push(format!(
+                "line {}: header names {:?}, not {:?}; name not changed",
+                n + 1,
+                v,
+                names.0
+            )),
+            None => bail!("header has no name"),
+        }
+    }
+
+    match args.get(1) {
+        Some((s, e, v)) if v.eq_ignore_ascii_case(old) => {
+            repl.push((*s, *e, same_case(v, new)));
+        }
+        Some((_, _, v)) => {
+            bail!("header has section {:?}, not {:?}", v, old);
+        }
+        None => bail!("header has no section"),
+    }
+
+    if !old.eq_ignore_ascii_case(new) && l.starts_with(".TH ") {
+        if let Some((s, e, v)) = args.get(4) {
+            match (map.title(old), map.title(&new.to_uppercase())) {
+                (Some(from), Some(to)) if v == from => {
+                    if from != to {
+                        repl.push((*s, *e, format!("\"{}\"", to)));
+                    }
+                }
+                (None, _) if old != main_section(old) => {
+                    /*
+                     * Subsections have their own titles, which do not change
+                     * unless the mapping says what they should be.
+                     */
+                }
+                (Some(_), Some(_)) => warnings.push(format!(
+                    "line {}: unexpected title {:?} for section {}",
+                    n + 1,
+                    v,
+                    old
+                )),
+                (from, _) => warnings.push(format!(
+                    "line {}: no title for section {}; title not changed",
+                    n + 1,
+                    if from.is_none() {
+                        old.to_string()
+                    } else {
+                        new.to_uppercase()
+                    }
+                )),
+            }
+        }
+    }
+
+    let mut nl = l[4..].to_string();
+    repl.sort_by_key(|r| std::cmp::Reverse(r.0));
+    for (s, e, v) in repl {
+        nl.replace_range(s..e, &v);
+    }
+    out[n] = format!("{}{}", &l[..4], nl);
+
+    Ok(out)
+}
+
+/**
+ * Work out where each page in this man directory (i.e., usr/src/man) must
+ * move, and how its header must change.
+ */
+pub fn plan(map: &Mapping, mandir: &Path) -> Result<Plan> {
+    let mut moves = Vec::new();
+    let mut warnings = Vec::new();
+
+    let mut dirs = std::fs::read_dir(mandir)
+        .with_context(|| format!("reading {:?}", mandir))?
+        .map(|ent| Ok(ent?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    dirs.sort();
+
+    for dir in dirs.iter().filter(|d| d.is_dir()) {
+        let name = dir.file_name().unwrap().to_string_lossy().to_string();
+        let sect = if let Some(sect) = name.strip_prefix("man") {
+            sect.to_string()
+        } else {
+            continue;
+        };
+
+        let mut files = std::fs::read_dir(dir)
+            .with_context(|| format!("reading {:?}", dir))?
+            .map(|ent| Ok(ent?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        files.sort();
+
+        for from in files {
+            let file = from.file_name().unwrap().to_string_lossy().to_string();
+            if file == "Makefile" || !from.is_file() {
+                continue;
+            }
+            let page = match file.strip_suffix(&format!(".{}", sect)) {
+                Some(page) => page,
+                None if map.section(&sect.to_uppercase()).is_some() => {
+                    warnings.push(format!("{}: not a page; not moved", file));
+                    continue;
+                }
+                None => continue,
+            };
+            let (new, name) = match map.page(&sect.to_uppercase(), page) {
+                Some((new, name)) => (new.to_lowercase(), name),
+                None => continue,
+            };
+
+            let input = std::fs::read_to_string(&from)
+                .with_context(|| format!("reading {:?}", from))?;
+            let mut rw = Rewrite::new(&input);
+
+            let mut w = Vec::new();
+            match rewrite_header(
+                map,
+                &rw.before,
+                &sect.to_uppercase(),
+                &new,
+                (page, &name),
+                &mut w,
+            ) {
+                Ok(after) => rw.after = after,
+                Err(e) => warnings.push(format!(
+                    "{}: {}; header not rewritten",
+                    from.display(),
+                    e
+                )),
+            };
+            warnings.extend(w.into_iter().map(|w| format!("{}: {}", file, w)));
+
+            moves.push(Move {
+                to: mandir
+                    .join(format!("man{}", new))
+                    .join(format!("{}.{}", name, new)),
+                from,
+                page: rw,
+            });
+        }
+    }
+
+    Ok(Plan { moves, warnings })
+}
+
+/**
+ * Sections move in a cycle, so a page may need to move to a path that is
+ * still occupied by another page that has yet to move.  Put the moves in an
+ * order where each destination has been vacated before it is used, breaking
+ * any cycles with a temporary name.  Returns a list of (from, to) renames.
+ */
+pub fn order(moves: &[Move]) -> Vec<(PathBuf, PathBuf)> {
+    let mut pending: BTreeMap<PathBuf, PathBuf> = moves
+        .iter()
+        .map(|m| (m.from.clone(), m.to.clone()))
+        .collect();
+    let mut out = Vec::new();
+
+    while !pending.is_empty() {
+        let ready = pending
+            .iter()
+            .find(|(_, to)| !pending.contains_key(*to))
+            .map(|(from, _)| from.clone());
+
+        if let Some(from) = ready {
+            let to = pending.remove(&from).unwrap();
+            out.push((from, to));
+            continue;
+        }
+
+        /*
+         * Everything left is part of a cycle.  Move one page aside, and
+         * finish moving it once its destination is free.
+         */
+        let (from, to) = pending.pop_first().unwrap();
+        let mut tmp = to.clone().into_os_string();
+        tmp.push(".renumber");
+        let tmp = PathBuf::from(tmp);
+        out.push((from, tmp.clone()));
+        pending.insert(tmp, to);
+    }
+
+    out
+}
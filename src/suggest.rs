@@ -0,0 +1,199 @@
+/*
+ * Suggest corrections for cross-references to pages that are not in the
+ * database.  In rough order of likelihood, a reference may be missing the
+ * subsection (e.g., "sd(7)" rather than "sd(7D)"), may use the wrong side of
+ * the renumbering, may simply name the wrong section, or may misspell the
+ * page name.
+ *
+ * Suggestions can be written to a file of fixes, one per line:
+ *
+ *      FILE  PAGE  SECT  NEWPAGE  NEWSECT
+ *
+ * separated by tabs, where FILE is the path of the page relative to the man
+ * directory (e.g., "man4/passwd.4").  The "xrefs" command applies these fixes
+ * before it renumbers, and fails if any of them names a reference that it
+ * does not find.  Lines that begin with "#" are ignored.
+ */
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+
+use super::mapping::{main_section, Mapping};
+use super::Database;
+
+const MAX_SUGGESTIONS: usize = 5;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Suggestion {
+    pub sect: String,
+    pub page: String,
+    pub why: String,
+    /**
+     * Lower is better.
+     */
+    pub rank: usize,
+}
+
+/**
+ * Fixes, keyed on (file, page, section), giving the new page and section.
+ */
+pub type Fixes = BTreeMap<(String, String, String), (String, String)>;
+
+/**
+ * The edit distance between two strings, counting the transposition of two
+ * adjacent characters as a single edit; e.g., "pritnf" is one edit from
+ * "printf".
+ */
+fn distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+
+    /*
+     * d[i][j] is the distance between the first i characters of a and the
+     * first j characters of b.
+     */
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j - 1] + cost)
+                .min(d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/**
+ * Find pages that a reference to page(sect), which is not in the database,
+ * may have been meant to name.  The best suggestions come first.
+ */
+pub fn suggest(
+    db: &Database,
+    map: &Mapping,
+    sect: &str,
+    page: &str,
+) -> Vec<Suggestion> {
+    let mut out = Vec::new();
+    let renumbered = map.page(sect, page).map(|(s, _)| s);
+
+    for r in db.named(page) {
+        if r.sect == sect {
+            continue;
+        }
+
+        let moved = map.page(&r.sect, &r.page).map(|(s, _)| s);
+        let (why, rank) = if main_section(&r.sect) == main_section(sect) {
+            (format!("page is in section {}", r.sect), 0)
+        } else if moved.as_deref() == Some(sect) {
+            (format!("section {} becomes {}", r.sect, sect), 1)
+        } else if renumbered.as_deref() == Some(r.sect.as_str()) {
+            (format!("section {} is now {}", sect, r.sect), 1)
+        } else {
+            (format!("page is in section {}", r.sect), 2)
+        };
+
+        out.push(Suggestion {
+            sect: r.sect.to_string(),
+            page: r.page.to_string(),
+            why,
+            rank,
+        });
+    }
+
+    for name in db.by_page.keys() {
+        /*
+         * Short names are close to a great many others, so allow fewer
+         * edits the shorter the name is.
+         */
+        let d = distance(name, page);
+        if d == 0 || d > 2 || d * 3 > page.chars().count() {
+            continue;
+        }
+
+        for r in db.named(name) {
+            if main_section(&r.sect) != main_section(sect) {
+                continue;
+            }
+
+            out.push(Suggestion {
+                sect: r.sect.to_string(),
+                page: r.page.to_string(),
+                why: format!(
+                    "{} edit{} away",
+                    d,
+                    if d == 1 { "" } else { "s" }
+                ),
+                rank: 2 + d,
+            });
+        }
+    }
+
+    out.sort_by(|a, b| {
+        a.rank
+            .cmp(&b.rank)
+            .then_with(|| (a.sect != sect).cmp(&(b.sect != sect)))
+            .then_with(|| a.sect.cmp(&b.sect))
+            .then_with(|| a.page.cmp(&b.page))
+    });
+    out.truncate(MAX_SUGGESTIONS);
+    out
+}
+
+/**
+ * Render the best suggestion as a line for the file of fixes.  If the choice
+ * is not clear, the line is commented out so that a person can decide.
+ */
+pub fn fix_line(
+    file: &str,
+    page: &str,
+    sect: &str,
+    suggestions: &[Suggestion],
+) -> Option<String> {
+    let best = suggestions.first()?;
+    let clear = suggestions.get(1).is_none_or(|s| s.rank > best.rank);
+    Some(format!(
+        "{}{}\t{}\t{}\t{}\t{}",
+        if clear { "" } else { "#" },
+        file,
+        page,
+        sect,
+        best.page,
+        best.sect
+    ))
+}
+
+pub fn load_fixes<P: AsRef<Path>>(path: P) -> Result<Fixes> {
+    let path = path.as_ref();
+    let input = std::fs::read_to_string(path)
+        .with_context(|| format!("reading {:?}", path))?;
+
+    let mut out = Fixes::new();
+    for (n, l) in input.lines().enumerate() {
+        if l.trim().is_empty() || l.starts_with('#') {
+            continue;
+        }
+
+        let t = l.split('\t').collect::<Vec<_>>();
+        if t.len() != 5 {
+            bail!("{:?} line {}: broken fix {:?}", path, n + 1, l);
+        }
+
+        out.insert(
+            (t[0].to_string(), t[1].to_string(), t[2].to_uppercase()),
+            (t[3].to_string(), t[4].to_uppercase()),
+        );
+    }
+
+    Ok(out)
+}
@@ -0,0 +1,225 @@
+/*
+ * Rewrite cross-references in the body of manual pages.  We recognise the
+ * forms in common use in illumos-gate:
+ *
+ *      \fBpage\fR(sect)        man(7) pages, and any other way of setting
+ *      .BR page (sect)         the name in bold or italics; we find these
+ *                              as the "kinds" command does, with roff.rs
+ *      .Xr page sect           mdoc(7) pages
+ *      page(sect)              plain text
+ *
+ * Only the page name and section of each reference are replaced; every other
+ * byte of the file is left as it was.  Plain text references are easily
+ * confused with other things (e.g., function calls in examples), so whether
+ * a reference is rewritten is left to the caller, who can check that it
+ * refers to a page that actually exists.
+ */
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use super::rewrite::{same_case, Rewrite};
+use super::roff::{arguments, references, Span, Text};
+
+/**
+ * Decides what a reference to a page in a section should become, if it
+ * should change.
+ */
+pub type Resolver<'a> = dyn FnMut(&str, &str) -> Option<(String, String)> + 'a;
+
+/**
+ * A reference, with the source of its page name and section.
+ */
+struct Found {
+    page: String,
+    sect: String,
+    page_at: Span,
+    sect_at: Span,
+}
+
+impl Found {
+    /**
+     * The source of the whole reference, from the start of the page name to
+     * the end of the section, on each line it covers.
+     */
+    fn spans(&self) -> Vec<Span> {
+        let (p, s) = (self.page_at, self.sect_at);
+        if p.0 == s.0 {
+            vec![(p.0, p.1, s.2)]
+        } else {
+            vec![p, s]
+        }
+    }
+}
+
+fn overlaps(a: &Found, b: &Found) -> bool {
+    a.spans().iter().any(|x| {
+        b.spans()
+            .iter()
+            .any(|y| x.0 == y.0 && x.1 < y.2 && y.1 < x.2)
+    })
+}
+
+/**
+ * Find the references in a single line (line "n" of the file) that roff.rs
+ * does not: those made with "Xr", and those in plain text.
+ */
+fn find(n: usize, line: &str) -> Vec<Found> {
+    lazy_static! {
+        static ref PLAIN: Regex = Regex::new(
+            r#"(?x)
+            (?P<page>[a-zA-Z_0-9][a-zA-Z_0-9+.-]*)
+            \(
+            (?P<sect>[0-9][0-9A-Za-z]*)
+            \)
+            "#
+        )
+        .unwrap();
+    }
+
+    if line.starts_with(r#".\""#) || line.starts_with(r#"'\""#) {
+        return Vec::new();
+    }
+
+    let mut out = Vec::new();
+    let mut push = |page: (usize, usize), sect: (usize, usize)| {
+        let f = Found {
+            page: line[page.0..page.1].to_string(),
+            sect: line[sect.0..sect.1].to_string(),
+            page_at: (n, page.0, page.1),
+            sect_at: (n, sect.0, sect.1),
+        };
+        if !out.iter().any(|o| overlaps(o, &f)) {
+            out.push(f);
+        }
+    };
+
+    if let Some(req) = line.strip_prefix('.') {
+        /*
+         * In mdoc, "Xr" may appear as the request itself, or as a callable
+         * macro later on the line.  We only rewrite unquoted arguments.
+         * Trailing punctuation is sometimes stuck to the section.
+         */
+        let args = arguments(req);
+        for w in args.windows(3) {
+            let (mac, page, sect) = (&w[0], &w[1], &w[2]);
+            let len = sect.2.trim_end_matches(|c| ".,:;)]?!".contains(c)).len();
+            if mac.2 == "Xr"
+                && page.1 - page.0 == page.2.len()
+                && sect.1 - sect.0 == sect.2.len()
+                && sect.2.starts_with(|c: char| c.is_ascii_digit())
+            {
+                push((page.0 + 1, page.1 + 1), (sect.0 + 1, sect.0 + 1 + len));
+            }
+        }
+    }
+
+    for m in PLAIN.captures_iter(line) {
+        let page = m.name("page").unwrap();
+        let sect = m.name("sect").unwrap();
+        if line[..page.start()].ends_with('\\') {
+            /*
+             * This is the tail of an escape; e.g., "\fR(4)".
+             */
+            continue;
+        }
+        push((page.start(), page.end()), (sect.start(), sect.end()));
+    }
+
+    out
+}
+
+pub fn rewrite(input: &str, resolve: &mut Resolver) -> Rewrite {
+    let mut rw = Rewrite::new(input);
+    let before = &rw.before;
+
+    let mut text = Text::default();
+    for (n, l) in before.iter().enumerate() {
+        text.line(n + 1, l);
+    }
+    let mut found = references(text.chars())
+        .into_iter()
+        .filter_map(|r| {
+            let (page_at, sect_at) = r.source?;
+            Some(Found {
+                page: r.page,
+                sect: r.sect,
+                page_at,
+                sect_at,
+            })
+        })
+        .collect::<Vec<_>>();
+    for (n, l) in before.iter().enumerate() {
+        for f in find(n + 1, l) {
+            if !found.iter().any(|o| overlaps(o, &f)) {
+                found.push(f);
+            }
+        }
+    }
+
+    let mut edits = Vec::new();
+    for f in found {
+        let (np, ns) = if let Some(new) = resolve(&f.page, &f.sect) {
+            new
+        } else {
+            continue;
+        };
+
+        let ns = same_case(&f.sect, &ns);
+
+        if np != f.page {
+            /*
+             * A name set with escapes has its hyphens escaped, too.
+             */
+            let (l, s, e) = f.page_at;
+            let np = if before[l - 1][s..e].contains('\\') {
+                np.replace('-', "\\-")
+            } else {
+                np
+            };
+            edits.push((f.page_at, np));
+        }
+        if ns != f.sect {
+            edits.push((f.sect_at, ns));
+        }
+    }
+
+    edits.sort_by_key(|e| std::cmp::Reverse((e.0 .0, e.0 .1)));
+    for ((l, s, e), v) in edits {
+        rw.after[l - 1].replace_range(s..e, &v);
+    }
+
+    rw
+}
+
+/**
+ * Find the source for every page in this man directory (i.e., usr/src/man).
+ */
+pub fn pages(mandir: &Path) -> Result<Vec<PathBuf>> {
+    let mut out = Vec::new();
+
+    for ent in std::fs::read_dir(mandir)
+        .with_context(|| format!("reading {:?}", mandir))?
+    {
+        let dir = ent?.path();
+        let name = dir.file_name().unwrap().to_string_lossy().to_string();
+        if !name.starts_with("man") || !dir.is_dir() {
+            continue;
+        }
+
+        for ent in std::fs::read_dir(&dir)
+            .with_context(|| format!("reading {:?}", dir))?
+        {
+            let p = ent?.path();
+            if p.is_file() && !p.ends_with("Makefile") {
+                out.push(p);
+            }
+        }
+    }
+
+    out.sort();
+    Ok(out)
+}